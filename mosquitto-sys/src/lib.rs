@@ -24,6 +24,16 @@ pub const MOSQ_ERR_EAI: ::libc::c_int = 15;
 pub const MOSQ_ERR_PROXY: ::libc::c_int = 16;
 pub type Enum_mosq_opt_t = ::libc::c_uint;
 pub const MOSQ_OPT_PROTOCOL_VERSION: ::libc::c_uint = 1;
+pub const MOSQ_LOG_NONE: ::libc::c_int = 0;
+pub const MOSQ_LOG_INFO: ::libc::c_int = 1;
+pub const MOSQ_LOG_NOTICE: ::libc::c_int = 2;
+pub const MOSQ_LOG_WARNING: ::libc::c_int = 4;
+pub const MOSQ_LOG_ERR: ::libc::c_int = 8;
+pub const MOSQ_LOG_DEBUG: ::libc::c_int = 16;
+pub const MOSQ_LOG_SUBSCRIBE: ::libc::c_int = 32;
+pub const MOSQ_LOG_UNSUBSCRIBE: ::libc::c_int = 64;
+pub const MOSQ_LOG_WEBSOCKETS: ::libc::c_int = 128;
+pub const MOSQ_LOG_ALL: ::libc::c_int = 65535;
 #[repr(C)]
 #[derive(Copy)]
 pub struct Struct_mosquitto_message {
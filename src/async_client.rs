@@ -0,0 +1,277 @@
+//! Async/await wrapper around `MqttClient`, enabled with the `tokio` feature.
+//!
+//! The network thread started by `connect` keeps doing all the work. The
+//! callbacks only complete futures: CONNACK resolves `connect`, the publish
+//! callback (PUBACK/PUBCOMP, or the write itself for QoS 0) resolves
+//! `publish` and SUBACK resolves `subscribe`. Acks are matched to requests
+//! by message id, SUBACKs by the filter the subscription registry has for
+//! them.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+
+use delivery::{Pending, Waiter};
+use subscription::SubscribeAck;
+use {ConnectReturnCode, DisconnectReason, Error, Message, Mid, MqttClient, Qos};
+
+impl<V> Waiter<V> for oneshot::Sender<V> {
+    fn complete(self, value: V) {
+        let _ = self.send(value);
+    }
+}
+
+type Acked<T> = Mutex<Pending<oneshot::Sender<Result<T, Error>>, Result<T, Error>>>;
+
+type SubscribeSender = oneshot::Sender<Result<SubscribeAck, Error>>;
+
+#[derive(Default)]
+struct Acks {
+    connect: Mutex<Option<oneshot::Sender<Result<(), Error>>>>,
+    publishes: Acked<Mid>,
+    // by filter, the message id changes when a subscription is sent again
+    subscribes: Mutex<HashMap<String, Vec<SubscribeSender>>>,
+    unsubscribes: Acked<Mid>,
+    messages: Mutex<Option<mpsc::UnboundedSender<Message>>>,
+}
+
+///Future returned by the `AsyncClient` requests. Resolves once the broker
+///acknowledged the request, or with `Error::NoConn` if the client is
+///dropped first.
+pub struct AckFuture<T> {
+    state: AckState<T>,
+}
+
+enum AckState<T> {
+    Waiting(oneshot::Receiver<Result<T, Error>>),
+    Failed(Option<Error>),
+}
+
+impl<T> AckFuture<T> {
+    fn waiting(rx: oneshot::Receiver<Result<T, Error>>) -> AckFuture<T> {
+        AckFuture { state: AckState::Waiting(rx) }
+    }
+
+    fn failed(err: Error) -> AckFuture<T> {
+        AckFuture { state: AckState::Failed(Some(err)) }
+    }
+}
+
+impl<T> Future for AckFuture<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut().state {
+            AckState::Waiting(ref mut rx) => {
+                match Pin::new(rx).poll(cx) {
+                    Poll::Ready(Ok(result)) => Poll::Ready(result),
+                    Poll::Ready(Err(_)) => Poll::Ready(Err(Error::NoConn)),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            AckState::Failed(ref mut err) => {
+                Poll::Ready(Err(err.take().expect("AckFuture polled after completion")))
+            }
+        }
+    }
+}
+
+///Stream of owned messages returned by `AsyncClient::messages`. Ends when
+///the client is dropped.
+pub struct MessageStream {
+    rx: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Stream for MessageStream {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Message>> {
+        self.get_mut().rx.poll_recv(cx)
+    }
+}
+
+///`MqttClient` with futures instead of callbacks.
+///
+///Takes over the connect, disconnect, publish, subscribe, unsubscribe and
+///message callbacks of the wrapped client. Routes and `messages` receivers
+///set up before wrapping keep working.
+///
+///```ignore
+///let mut client = AsyncClient::new(MqttClient::new("scooter-1", true)?);
+///client.connect("localhost", 1883).await?;
+///let ack = client.subscribe("fleet/commands/#", Qos::AtLeastOnce).await?;
+///client.publish("fleet/status", b"online", Qos::AtLeastOnce, true).await?;
+///
+///let mut messages = client.messages();
+///while let Some(msg) = messages.next().await {
+///    println!("@@@ {} = {:?}", msg.topic, msg.payload);
+///}
+///```
+pub struct AsyncClient {
+    client: MqttClient,
+    acks: Arc<Acks>,
+}
+
+impl AsyncClient {
+    pub fn new(mut client: MqttClient) -> AsyncClient {
+        let acks = Arc::new(Acks::default());
+
+        let a = acks.clone();
+        client.onconnect_callback(move |rc: ConnectReturnCode| {
+            if let Some(tx) = a.connect.lock().unwrap().take() {
+                let result = if rc.is_accepted() { Ok(()) } else { Err(Error::Refused(rc)) };
+                let _ = tx.send(result);
+            }
+        });
+
+        let a = acks.clone();
+        client.ondisconnect_callback(move |reason| {
+            // Lost before the CONNACK made it through
+            if let Some(tx) = a.connect.lock().unwrap().take() {
+                let err = match reason {
+                    DisconnectReason::Requested => Error::NoConn,
+                    DisconnectReason::Unexpected(e) => e,
+                };
+                let _ = tx.send(Err(err));
+            }
+        });
+
+        let a = acks.clone();
+        client.onpublish_callback(move |mid| a.publishes.lock().unwrap().complete(mid, Ok(mid)));
+
+        let a = acks.clone();
+        client.onsubscribe_callback(move |ack: SubscribeAck| {
+            // No filter: a SUBACK for a subscription that was sent again
+            // or dropped since
+            let waiting = match ack.filter {
+                Some(ref filter) => a.subscribes.lock().unwrap().remove(filter),
+                None => None,
+            };
+            for tx in waiting.into_iter().flatten() {
+                let _ = tx.send(Ok(ack.clone()));
+            }
+        });
+
+        let a = acks.clone();
+        client.onunsubscribe_callback(move |mid| a.unsubscribes.lock().unwrap().complete(mid, Ok(mid)));
+
+        let a = acks.clone();
+        client.onmesssage_callback(move |msg| {
+            if let Some(ref tx) = *a.messages.lock().unwrap() {
+                let _ = tx.send(msg.to_message());
+            }
+        });
+
+        AsyncClient { client, acks }
+    }
+
+    ///The wrapped client, for everything that doesn't wait on the broker
+    pub fn client(&self) -> &MqttClient {
+        &self.client
+    }
+
+    ///Connects and resolves once the broker accepted the connection. A refusal
+    ///resolves to `Error::Refused` with the CONNACK code.
+    ///
+    ///Opening the socket still blocks the calling thread, only the wait for
+    ///CONNACK is asynchronous
+    pub fn connect(&mut self, host: &str, port: i32) -> AckFuture<()> {
+        let (tx, rx) = oneshot::channel();
+        // CONNACK can arrive as soon as the network thread is running
+        *self.acks.connect.lock().unwrap() = Some(tx);
+
+        match self.client.connect(host, port) {
+            Ok(_) => AckFuture::waiting(rx),
+            Err(e) => {
+                self.acks.connect.lock().unwrap().take();
+                AckFuture::failed(e)
+            }
+        }
+    }
+
+    ///Publishes and resolves to the message id once the broker acknowledged
+    ///it (PUBACK for QoS 1, PUBCOMP for QoS 2). QoS 0 messages resolve once
+    ///they are written to the socket
+    pub fn publish<P>(&self, topic: &str, payload: P, qos: Qos, retain: bool) -> AckFuture<Mid>
+        where P: AsRef<[u8]>
+    {
+        request(&self.acks.publishes, || self.client.publish(topic, payload, qos, retain).map(|token| token.mid()))
+    }
+
+    ///Subscribes and resolves to the broker's SUBACK. Without a connection
+    ///the subscription is queued and resolves after the next connect.
+    ///Subscribing to a filter again before its SUBACK resolves both requests
+    ///with the first SUBACK for it
+    pub fn subscribe(&self, topic: &str, qos: Qos) -> AckFuture<SubscribeAck> {
+        // Wait by filter before sending. Whichever message id the SUBACK
+        // comes back with, after a resubscribe too, the registry knows the
+        // filter it is for
+        let (tx, rx) = oneshot::channel();
+        self.acks.subscribes.lock().unwrap().entry(topic.to_string()).or_default().push(tx);
+
+        match self.client.subscribe(topic, qos) {
+            Ok(_) => AckFuture::waiting(rx),
+            Err(e) => {
+                drop(rx);
+                self.drop_closed_subscribes(topic);
+                AckFuture::failed(e)
+            }
+        }
+    }
+
+    ///Forgets waiters for `topic` whose future is gone
+    fn drop_closed_subscribes(&self, topic: &str) {
+        let mut subscribes = self.acks.subscribes.lock().unwrap();
+        if let Some(waiting) = subscribes.get_mut(topic) {
+            waiting.retain(|tx| !tx.is_closed());
+        }
+        if subscribes.get(topic).is_some_and(|w| w.is_empty()) {
+            subscribes.remove(topic);
+        }
+    }
+
+    ///Unsubscribes and resolves to the message id once the broker acknowledged it
+    pub fn unsubscribe(&self, topic: &str) -> AckFuture<Mid> {
+        let future = request(&self.acks.unsubscribes, || self.client.unsubscribe(topic));
+        // Their SUBACK can't be matched anymore. Dropping them resolves
+        // the futures with `Error::NoConn`
+        self.acks.subscribes.lock().unwrap().remove(topic);
+        future
+    }
+
+    ///Returns a stream of every received message. Calling this again
+    ///replaces the previous stream, which then ends
+    pub fn messages(&self) -> MessageStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.acks.messages.lock().unwrap() = Some(tx);
+        MessageStream { rx }
+    }
+
+    pub fn disconnect(&self) -> Result<(), Error> {
+        self.client.disconnect()
+    }
+}
+
+///Sends a packet with `send` and waits for the ack of the message id it
+///returns. Acks are only kept for requests made here
+fn request<T, F>(pending: &Acked<T>, send: F) -> AckFuture<T>
+    where F: FnOnce() -> Result<Mid, Error>
+{
+    pending.lock().unwrap().expect();
+    match send() {
+        Ok(mid) => {
+            let (tx, rx) = oneshot::channel();
+            pending.lock().unwrap().attach(mid, tx);
+            AckFuture::waiting(rx)
+        }
+        Err(e) => {
+            pending.lock().unwrap().cancel();
+            AckFuture::failed(e)
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Receiving messages on any thread instead of in callbacks
+//!
+//! The message callback pushes owned copies into a queue that `Messages`
+//! reads from. Bounded queues apply an `Overflow` policy when the consumer
+//! can't keep up.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use message::{Message, MessageRef};
+
+///What to do with a new message when a bounded queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    ///Wait until the consumer makes room. This stalls the network thread,
+    ///including keep alive pings, for as long as the queue stays full
+    Block,
+    ///Throw away the oldest queued message
+    DropOldest,
+    ///Throw away the new message
+    DropNewest,
+}
+
+struct State {
+    queue: VecDeque<Message>,
+    dropped: u64,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    overflow: Overflow,
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+///Receiving end of `MqttClient::messages`. Works like `std::sync::mpsc::Receiver`
+///and reports `Disconnected` once the client is dropped and the queue is empty.
+///
+///```ignore
+///let messages = client.messages();
+///thread::spawn(move || {
+///    for msg in messages {
+///        println!("@@@ {} = {:?}", msg.topic, msg.payload);
+///    }
+///});
+///```
+pub struct Messages {
+    shared: Arc<Shared>,
+}
+
+///Creates a queue. A `capacity` of `None` means unbounded. A bound of 0 is
+///treated as 1
+pub fn channel(capacity: Option<usize>, overflow: Overflow) -> (Sender, Messages) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            dropped: 0,
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity: capacity.map(|c| c.max(1)),
+        overflow,
+    });
+
+    (Sender { shared: shared.clone() }, Messages { shared })
+}
+
+impl Sender {
+    pub fn send(&self, message: &MessageRef) {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if !state.receiver_alive || !state.sender_alive {
+            return;
+        }
+
+        if let Some(capacity) = shared.capacity {
+            while state.queue.len() >= capacity {
+                match shared.overflow {
+                    Overflow::Block => {
+                        state = shared.not_full.wait(state).unwrap();
+                        if !state.receiver_alive || !state.sender_alive {
+                            return;
+                        }
+                    }
+                    Overflow::DropOldest => {
+                        state.queue.pop_front();
+                        state.dropped += 1;
+                    }
+                    Overflow::DropNewest => {
+                        state.dropped += 1;
+                        return;
+                    }
+                }
+            }
+        }
+
+        state.queue.push_back(message.to_message());
+        shared.not_empty.notify_one();
+    }
+
+    ///Ends the queue like dropping the sender does, and makes a `send`
+    ///blocked on a full queue give up
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().sender_alive = false;
+        self.shared.not_empty.notify_all();
+        self.shared.not_full.notify_all();
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Messages {
+    ///Blocks until a message arrives
+    pub fn recv(&self) -> Result<Message, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(message) = state.queue.pop_front() {
+                self.shared.not_full.notify_one();
+                return Ok(message);
+            }
+            if !state.sender_alive {
+                return Err(RecvError);
+            }
+            state = self.shared.not_empty.wait(state).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<Message, TryRecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(message) => {
+                self.shared.not_full.notify_one();
+                Ok(message)
+            }
+            None if state.sender_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Message, RecvTimeoutError> {
+        // A timeout too large to add to the current time waits without a limit
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(message) = state.queue.pop_front() {
+                self.shared.not_full.notify_one();
+                return Ok(message);
+            }
+            if !state.sender_alive {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.shared.not_empty.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.shared.not_empty.wait(state).unwrap(),
+            };
+        }
+    }
+
+    ///Messages currently waiting in the queue
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///Number of messages thrown away by the overflow policy so far
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Message;
+
+    ///Blocks for the next message. Ends when the client is dropped
+    fn next(&mut self) -> Option<Message> {
+        self.recv().ok()
+    }
+}
+
+impl Drop for Messages {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_alive = false;
+        // Unblock a network thread waiting for room
+        self.shared.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::thread;
+
+    use bindings;
+
+    fn send(sender: &Sender, topic: &str) {
+        let topic = CString::new(topic).unwrap();
+        let raw = bindings::Struct_mosquitto_message {
+            mid: 1,
+            topic: topic.as_ptr() as *mut _,
+            payload: ::std::ptr::null_mut(),
+            payloadlen: 0,
+            qos: 0,
+            retain: 0,
+        };
+        sender.send(&unsafe { MessageRef::from_raw(&raw) });
+    }
+
+    fn topics(messages: &Messages) -> Vec<String> {
+        let mut topics = Vec::new();
+        while let Ok(msg) = messages.try_recv() {
+            topics.push(msg.topic);
+        }
+        topics
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (sender, messages) = channel(Some(2), Overflow::DropOldest);
+        for topic in &["a", "b", "c"] {
+            send(&sender, topic);
+        }
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages.dropped(), 1);
+        assert_eq!(topics(&messages), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (sender, messages) = channel(Some(2), Overflow::DropNewest);
+        for topic in &["a", "b", "c"] {
+            send(&sender, topic);
+        }
+        assert_eq!(messages.dropped(), 1);
+        assert_eq!(topics(&messages), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let (sender, messages) = channel(Some(1), Overflow::Block);
+        send(&sender, "a");
+        let blocked = thread::spawn(move || {
+            send(&sender, "b");
+            sender
+        });
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages.recv().unwrap().topic, "a");
+        let _sender = blocked.join().unwrap();
+        assert_eq!(messages.recv().unwrap().topic, "b");
+        assert_eq!(messages.dropped(), 0);
+    }
+
+    #[test]
+    fn close_unblocks_sender() {
+        let (sender, messages) = channel(Some(1), Overflow::Block);
+        let sender = Arc::new(sender);
+        send(&sender, "a");
+        let s = sender.clone();
+        let blocked = thread::spawn(move || send(&s, "b"));
+
+        thread::sleep(Duration::from_millis(20));
+        sender.close();
+        blocked.join().unwrap();
+        assert_eq!(topics(&messages), vec!["a"]);
+        assert_eq!(messages.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let (sender, messages) = channel(None, Overflow::Block);
+        assert_eq!(messages.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+
+        send(&sender, "a");
+        assert_eq!(messages.recv_timeout(Duration::from_millis(10)).unwrap().topic, "a");
+
+        send(&sender, "b");
+        assert_eq!(messages.recv_timeout(Duration::from_secs(u64::MAX)).unwrap().topic, "b");
+
+        drop(sender);
+        assert_eq!(messages.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Disconnected));
+    }
+}
@@ -0,0 +1,233 @@
+//! Tracking of outgoing messages until libmosquitto reports them delivered
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use Mid;
+
+struct State {
+    complete: Mutex<bool>,
+    done: Condvar,
+}
+
+impl State {
+    fn new(complete: bool) -> State {
+        State {
+            complete: Mutex::new(complete),
+            done: Condvar::new(),
+        }
+    }
+
+    fn complete(&self) {
+        *self.complete.lock().unwrap() = true;
+        self.done.notify_all();
+    }
+}
+
+///Returned by `publish`. Completes when the publish callback fires for its
+///message id: after PUBACK for QoS 1, PUBCOMP for QoS 2, and once the
+///message is written to the socket for QoS 0.
+///
+///```ignore
+///let token = client.publish("vehicles/42/odometer", b"1337", Qos::AtLeastOnce, false)?;
+///if !token.wait(Duration::from_secs(5)) {
+///    println!("@@@ Message {} not acknowledged yet", token.mid());
+///}
+///```
+#[derive(Clone)]
+pub struct DeliveryToken {
+    mid: Mid,
+    state: Arc<State>,
+}
+
+impl DeliveryToken {
+    ///Message id libmosquitto assigned to the message
+    pub fn mid(&self) -> Mid {
+        self.mid
+    }
+
+    pub fn is_complete(&self) -> bool {
+        *self.state.complete.lock().unwrap()
+    }
+
+    ///Blocks until the message is delivered or `timeout` passes.
+    ///Returns whether it was delivered. A timeout too large to add to the
+    ///current time waits for as long as it takes
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut complete = self.state.complete.lock().unwrap();
+        while !*complete {
+            complete = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.state.done.wait_timeout(complete, deadline - now).unwrap().0
+                }
+                None => self.state.done.wait(complete).unwrap(),
+            };
+        }
+        true
+    }
+}
+
+impl fmt::Debug for DeliveryToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeliveryToken")
+         .field("mid", &self.mid)
+         .field("complete", &self.is_complete())
+         .finish()
+    }
+}
+
+///Something waiting for the ack of a packet
+pub trait Waiter<V> {
+    fn complete(self, value: V);
+}
+
+impl Waiter<()> for Arc<State> {
+    fn complete(self, _: ()) {
+        State::complete(&self)
+    }
+}
+
+///Waiters for acks, by message id
+pub struct Pending<W, V> {
+    waiting: HashMap<Mid, W>,
+    // The network thread can ack a packet before the caller got its mid
+    // back from libmosquitto and started waiting
+    early: HashMap<Mid, V>,
+    // Callers between `expect` and `attach`. Only while there are any can
+    // an ack be early, anything else is an ack nobody asked for
+    expecting: usize,
+}
+
+impl<W, V> Default for Pending<W, V> {
+    fn default() -> Pending<W, V> {
+        Pending {
+            waiting: HashMap::new(),
+            early: HashMap::new(),
+            expecting: 0,
+        }
+    }
+}
+
+impl<W: Waiter<V>, V> Pending<W, V> {
+    ///Call before sending a packet whose ack `attach` is going to wait for
+    pub fn expect(&mut self) {
+        self.expecting += 1;
+    }
+
+    ///Waits for the ack of `mid`, which the packet announced by `expect`
+    ///was sent with. Completes right away if the ack came first
+    pub fn attach(&mut self, mid: Mid, waiter: W) {
+        match self.early.remove(&mid) {
+            Some(value) => waiter.complete(value),
+            None => {
+                self.waiting.insert(mid, waiter);
+            }
+        }
+        self.cancel();
+    }
+
+    ///Takes back an `expect` whose packet couldn't be sent
+    pub fn cancel(&mut self) {
+        self.expecting = self.expecting.saturating_sub(1);
+        if self.expecting == 0 {
+            self.early.clear();
+        }
+    }
+
+    ///Called from the ack callback
+    pub fn complete(&mut self, mid: Mid, value: V) {
+        match self.waiting.remove(&mid) {
+            Some(waiter) => waiter.complete(value),
+            None if self.expecting > 0 => {
+                self.early.insert(mid, value);
+            }
+            None => (),
+        }
+    }
+}
+
+///Tokens of messages that haven't been delivered yet
+#[derive(Default)]
+pub struct Deliveries {
+    pending: Pending<Arc<State>, ()>,
+}
+
+impl Deliveries {
+    ///Call before `mosquitto_publish`
+    pub fn expect(&mut self) {
+        self.pending.expect()
+    }
+
+    ///Token for a message `mosquitto_publish` just assigned `mid` to
+    pub fn track(&mut self, mid: Mid) -> DeliveryToken {
+        let state = Arc::new(State::new(false));
+        self.pending.attach(mid, state.clone());
+        DeliveryToken { mid, state }
+    }
+
+    ///Call if `mosquitto_publish` failed
+    pub fn cancel(&mut self) {
+        self.pending.cancel()
+    }
+
+    ///Called from the publish callback
+    pub fn complete(&mut self, mid: Mid) {
+        self.pending.complete(mid, ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_before_track() {
+        let mut deliveries = Deliveries::default();
+        deliveries.expect();
+        deliveries.complete(7);
+        assert!(deliveries.track(7).is_complete());
+    }
+
+    #[test]
+    fn track_before_complete() {
+        let mut deliveries = Deliveries::default();
+        deliveries.expect();
+        let token = deliveries.track(7);
+        assert!(!token.is_complete());
+        deliveries.complete(7);
+        assert!(token.wait(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn unexpected_acks_are_dropped() {
+        let mut deliveries = Deliveries::default();
+        deliveries.complete(7);
+        deliveries.expect();
+        assert!(!deliveries.track(7).is_complete());
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let mut deliveries = Deliveries::default();
+        deliveries.expect();
+        let token = deliveries.track(7);
+        let start = Instant::now();
+        assert!(!token.wait(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn wait_without_deadline() {
+        let mut deliveries = Deliveries::default();
+        deliveries.expect();
+        deliveries.complete(7);
+        assert!(deliveries.track(7).wait(Duration::from_secs(u64::MAX)));
+    }
+}
@@ -0,0 +1,170 @@
+//! Errors reported by the mosquitto library and by the rust wrappers around it
+
+use std::error;
+use std::ffi::{CStr, NulError};
+use std::fmt;
+use std::io;
+
+use bindings;
+use libc;
+use ConnectReturnCode;
+
+///Everything that can go wrong while talking to the broker.
+///
+///One variant per `MOSQ_ERR_*` code returned by libmosquitto, plus
+///the failures that can only happen on the rust side before a call
+///ever reaches C.
+#[derive(Debug)]
+pub enum Error {
+    ///A non blocking connect is still in progress
+    ConnPending,
+    ///Out of memory
+    NoMem,
+    ///Broker or client violated the MQTT protocol
+    Protocol,
+    ///Invalid arguments were passed to the library
+    Inval,
+    ///Client is not connected to a broker
+    NoConn,
+    ///Connection was refused by the broker
+    ConnRefused,
+    ///Requested item could not be found
+    NotFound,
+    ///Connection to the broker was lost
+    ConnLost,
+    ///TLS setup or handshake failed
+    Tls,
+    ///Payload is larger than what MQTT allows
+    PayloadSize,
+    ///Feature is not supported by this build of libmosquitto
+    NotSupported,
+    ///Authentication failed
+    Auth,
+    ///Access was denied by the broker ACL
+    AclDenied,
+    ///Unknown libmosquitto error
+    Unknown,
+    ///System call failed. Holds the OS error that caused it
+    Errno(io::Error),
+    ///Host name lookup failed
+    Eai,
+    ///SOCKS5 proxy error
+    Proxy,
+    ///Return code that this crate doesn't know about
+    Other(i32),
+    ///A string handed to the library contained an interior NUL byte
+    Nul(NulError),
+    ///Client options failed validation before reaching libmosquitto
+    InvalidOption(&'static str),
+    ///Topic is not valid for the operation it was used in
+    InvalidTopic(String),
+    ///Broker answered the connection with a CONNACK other than `Accepted`
+    Refused(ConnectReturnCode),
+    ///Payload of this many bytes is over the MQTT limit of 268,435,455.
+    ///Caught before reaching libmosquitto, which would report `PayloadSize`
+    PayloadTooLarge(usize),
+    ///Broker didn't accept the connection within `ClientOptions::connect_timeout`
+    ConnectTimeout,
+}
+
+impl Error {
+    ///Converts a libmosquitto return code into a result.
+    ///`MOSQ_ERR_ERRNO` picks up the OS error of the calling thread,
+    ///so call this right after the failing function.
+    pub fn from_rc(rc: libc::c_int) -> Result<(), Error> {
+        let err = match rc {
+            bindings::MOSQ_ERR_SUCCESS => return Ok(()),
+            bindings::MOSQ_ERR_CONN_PENDING => Error::ConnPending,
+            bindings::MOSQ_ERR_NOMEM => Error::NoMem,
+            bindings::MOSQ_ERR_PROTOCOL => Error::Protocol,
+            bindings::MOSQ_ERR_INVAL => Error::Inval,
+            bindings::MOSQ_ERR_NO_CONN => Error::NoConn,
+            bindings::MOSQ_ERR_CONN_REFUSED => Error::ConnRefused,
+            bindings::MOSQ_ERR_NOT_FOUND => Error::NotFound,
+            bindings::MOSQ_ERR_CONN_LOST => Error::ConnLost,
+            bindings::MOSQ_ERR_TLS => Error::Tls,
+            bindings::MOSQ_ERR_PAYLOAD_SIZE => Error::PayloadSize,
+            bindings::MOSQ_ERR_NOT_SUPPORTED => Error::NotSupported,
+            bindings::MOSQ_ERR_AUTH => Error::Auth,
+            bindings::MOSQ_ERR_ACL_DENIED => Error::AclDenied,
+            bindings::MOSQ_ERR_UNKNOWN => Error::Unknown,
+            bindings::MOSQ_ERR_ERRNO => Error::Errno(io::Error::last_os_error()),
+            bindings::MOSQ_ERR_EAI => Error::Eai,
+            bindings::MOSQ_ERR_PROXY => Error::Proxy,
+            n => Error::Other(n),
+        };
+
+        Err(err)
+    }
+
+    ///Errors after which a network loop should reconnect instead of giving up.
+    ///Same split as `mosquitto_loop_forever`
+    pub(crate) fn is_recoverable(&self) -> bool {
+        matches!(*self, Error::NoConn | Error::ConnLost | Error::ConnRefused | Error::Errno(_))
+    }
+
+    ///The libmosquitto return code for this error, if it has one
+    pub fn code(&self) -> Option<i32> {
+        let rc = match *self {
+            Error::ConnPending => bindings::MOSQ_ERR_CONN_PENDING,
+            Error::NoMem => bindings::MOSQ_ERR_NOMEM,
+            Error::Protocol => bindings::MOSQ_ERR_PROTOCOL,
+            Error::Inval => bindings::MOSQ_ERR_INVAL,
+            Error::NoConn => bindings::MOSQ_ERR_NO_CONN,
+            Error::ConnRefused => bindings::MOSQ_ERR_CONN_REFUSED,
+            Error::NotFound => bindings::MOSQ_ERR_NOT_FOUND,
+            Error::ConnLost => bindings::MOSQ_ERR_CONN_LOST,
+            Error::Tls => bindings::MOSQ_ERR_TLS,
+            Error::PayloadSize => bindings::MOSQ_ERR_PAYLOAD_SIZE,
+            Error::NotSupported => bindings::MOSQ_ERR_NOT_SUPPORTED,
+            Error::Auth => bindings::MOSQ_ERR_AUTH,
+            Error::AclDenied => bindings::MOSQ_ERR_ACL_DENIED,
+            Error::Unknown => bindings::MOSQ_ERR_UNKNOWN,
+            Error::Errno(_) => bindings::MOSQ_ERR_ERRNO,
+            Error::Eai => bindings::MOSQ_ERR_EAI,
+            Error::Proxy => bindings::MOSQ_ERR_PROXY,
+            Error::Other(n) => n,
+            Error::Refused(_) => bindings::MOSQ_ERR_CONN_REFUSED,
+            Error::Nul(_) | Error::InvalidOption(_) | Error::InvalidTopic(_) | Error::PayloadTooLarge(_) |
+            Error::ConnectTimeout => return None,
+        };
+
+        Some(rc)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Errno(ref e) => write!(f, "{}", e),
+            Error::Nul(ref e) => write!(f, "{}", e),
+            Error::InvalidOption(msg) => write!(f, "Invalid client option: {}", msg),
+            Error::InvalidTopic(ref topic) => write!(f, "Invalid topic: {:?}", topic),
+            Error::Refused(rc) => write!(f, "Connection refused: {}", rc),
+            Error::PayloadTooLarge(len) => write!(f, "Payload of {} bytes is over the MQTT limit", len),
+            Error::ConnectTimeout => write!(f, "Timed out waiting for the broker to accept the connection"),
+            _ => {
+                let rc = self.code().unwrap_or(bindings::MOSQ_ERR_UNKNOWN);
+                // mosquitto_strerror returns pointers to static strings
+                let msg = unsafe { CStr::from_ptr(bindings::mosquitto_strerror(rc)) };
+                write!(f, "{}", msg.to_string_lossy())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Errno(ref e) => Some(e),
+            Error::Nul(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Error {
+        Error::Nul(e)
+    }
+}
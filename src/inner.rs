@@ -0,0 +1,281 @@
+//! Callback state shared with the libmosquitto network thread.
+//!
+//! `MqttClient` is a plain value that users move around (into vectors, out of
+//! functions ...), so its address can't be handed to libmosquitto as userdata.
+//! Everything the callbacks need lives in `Inner` instead, which is boxed by
+//! the client and never moves until the client is dropped.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use bindings;
+use channel::Sender;
+use delivery::Deliveries;
+use libc;
+use logging;
+use message::MessageRef;
+use options::{self, ProtocolVersion, ReconnectPolicy};
+use router::Router;
+use state::{ConnectionState, StateTracker};
+use subscription::{Registry, SubscribeAck};
+use {ConnectReturnCode, Error};
+
+pub type IntCallback = Box<dyn FnMut(i32) + Send>;
+pub type MessageCallback = Box<dyn Fn(&MessageRef) + Send>;
+pub type SubscribeCallback = Box<dyn FnMut(SubscribeAck) + Send>;
+
+///Raw mosquitto handle and its callback state, for threads started by a
+///connect. Both stay valid while the handle is in `ConnectTimeout::handle`
+pub struct Handle {
+    pub mosq: *mut bindings::Struct_mosquitto,
+    pub inner: *const Inner,
+}
+
+unsafe impl Send for Handle {}
+
+///What the connect timer and async connect threads need. Shared with them
+///because they can outlive the client
+#[derive(Default)]
+pub struct ConnectTimeout {
+    // set once the handle exists, cleared by `Drop` before destroying it
+    pub handle: Mutex<Option<Handle>>,
+    // bumped by every connect so timers of earlier ones do nothing
+    pub attempt: AtomicUsize,
+    pub expired: AtomicBool,
+}
+
+///Bookkeeping of the `loop_start` thread. Shared with the connect timer
+#[derive(Default)]
+pub struct Background {
+    // a thread was started and hasn't been joined yet
+    pub running: Mutex<bool>,
+    // `mosquitto_disconnect` was called, which makes the thread end on its own
+    pub ending: AtomicBool,
+}
+
+impl Background {
+    ///Starts the thread unless it is running already
+    pub unsafe fn start(&self, mosq: *mut bindings::Struct_mosquitto) -> Result<(), Error> {
+        let mut running = self.running.lock().unwrap();
+        if !*running {
+            Error::from_rc(bindings::mosquitto_loop_start(mosq))?;
+            *running = true;
+        }
+        Ok(())
+    }
+}
+
+pub struct Inner {
+    pub icallbacks: Mutex<HashMap<String, IntCallback>>, // integer callbacks
+    pub mcallbacks: Mutex<HashMap<String, MessageCallback>>, // message callbacks
+    // queue behind `MqttClient::messages`. Not a callback so a send that
+    // blocks doesn't keep `mcallbacks` locked
+    pub channel: Mutex<Option<Arc<Sender>>>,
+    pub subscribe_callback: Mutex<Option<SubscribeCallback>>,
+    pub subscriptions: Mutex<Registry>,
+    pub router: Mutex<Router>,
+    // publishes waiting for the publish callback
+    pub deliveries: Mutex<Deliveries>,
+    // protocol version the next connect will use and whether to fall back to 3.1
+    pub protocol_version: Mutex<ProtocolVersion>,
+    pub protocol_fallback: AtomicBool,
+    // read by the disconnect callback to jitter or stop reconnects
+    pub reconnect_policy: Mutex<ReconnectPolicy>,
+    pub auto_reconnect: AtomicBool,
+    pub state: StateTracker,
+    // set by `disconnect`, cleared by `connect` and `reconnect`
+    pub disconnect_requested: AtomicBool,
+    // last CONNACK refusal, unless it made the protocol fall back
+    pub refused: Mutex<Option<ConnectReturnCode>>,
+    pub connect_timeout: Arc<ConnectTimeout>,
+    pub background: Arc<Background>,
+    // shared with `StopHandle`s to end `run`
+    pub stop: Arc<AtomicBool>,
+    // id used to tell clients apart in the logs
+    pub client_id: Mutex<String>,
+}
+
+impl Default for Inner {
+    fn default() -> Inner {
+        Inner {
+            icallbacks: Mutex::new(HashMap::new()),
+            mcallbacks: Mutex::new(HashMap::new()),
+            channel: Mutex::new(None),
+            subscribe_callback: Mutex::new(None),
+            subscriptions: Mutex::new(Registry::default()),
+            router: Mutex::new(Router::default()),
+            deliveries: Mutex::new(Deliveries::default()),
+            protocol_version: Mutex::new(ProtocolVersion::V31),
+            protocol_fallback: AtomicBool::new(false),
+            reconnect_policy: Mutex::new(ReconnectPolicy::default()),
+            auto_reconnect: AtomicBool::new(true),
+            state: StateTracker::default(),
+            disconnect_requested: AtomicBool::new(false),
+            refused: Mutex::new(None),
+            connect_timeout: Arc::new(ConnectTimeout::default()),
+            background: Arc::new(Background::default()),
+            stop: Arc::new(AtomicBool::new(false)),
+            client_id: Mutex::new(String::new()),
+        }
+    }
+}
+
+impl Inner {
+    pub fn call_int(&self, name: &str, val: i32) {
+        let mut callbacks = self.icallbacks.lock().unwrap();
+        if let Some(cb) = callbacks.get_mut(name) {
+            cb(val)
+        }
+    }
+}
+
+///Points libmosquitto at `inner` and registers all the callback wrappers.
+///Callbacks that the user hasn't set yet are simply skipped by the wrappers.
+///
+///`inner` has to outlive `mosq`. Needs to be called again after
+///`mosquitto_reinitialise` because it wipes both userdata and callbacks.
+pub unsafe fn register(mosq: *mut bindings::Struct_mosquitto, inner: &Inner) {
+    bindings::mosquitto_user_data_set(mosq, inner as *const Inner as *mut libc::c_void);
+    bindings::mosquitto_connect_callback_set(mosq, Some(onconnect_wrapper));
+    bindings::mosquitto_disconnect_callback_set(mosq, Some(ondisconnect_wrapper));
+    bindings::mosquitto_subscribe_callback_set(mosq, Some(onsubscribe_wrapper));
+    bindings::mosquitto_unsubscribe_callback_set(mosq, Some(onunsubscribe_wrapper));
+    bindings::mosquitto_publish_callback_set(mosq, Some(onpublish_wrapper));
+    bindings::mosquitto_message_callback_set(mosq, Some(onmessage_wrapper));
+    bindings::mosquitto_log_callback_set(mosq, Some(onlog_wrapper));
+}
+
+unsafe extern "C" fn onconnect_wrapper(mqtt: *mut bindings::Struct_mosquitto,
+                                       inner: *mut libc::c_void,
+                                       val: libc::c_int) {
+    let inner = &*(inner as *const Inner);
+    let rc = ConnectReturnCode::from(val);
+    let mut fell_back = false;
+
+    if rc == ConnectReturnCode::UnacceptableProtocolVersion &&
+       inner.protocol_fallback.load(Ordering::SeqCst) {
+        let mut version = inner.protocol_version.lock().unwrap();
+        // The network thread reconnects on its own after a refusal. Just make
+        // sure it speaks 3.1 the next time
+        if *version == ProtocolVersion::V311 &&
+           options::set_protocol_version(mqtt, ProtocolVersion::V31).is_ok() {
+            *version = ProtocolVersion::V31;
+            fell_back = true;
+        }
+    }
+    *inner.refused.lock().unwrap() = if rc.is_accepted() || fell_back { None } else { Some(rc) };
+
+    if rc.is_accepted() {
+        inner.state.set(ConnectionState::Connected);
+        if let Err(e) = inner.subscriptions.lock().unwrap().resubscribe(mqtt) {
+            let id = inner.client_id.lock().unwrap();
+            logging::log(Some(&id), logging::Level::Warn, &format!("resubscribing failed: {}", e));
+        }
+    }
+
+    inner.call_int("on_connect", val);
+}
+
+unsafe extern "C" fn ondisconnect_wrapper(mqtt: *mut bindings::Struct_mosquitto,
+                                          inner: *mut libc::c_void,
+                                          rc: libc::c_int) {
+    let inner = &*(inner as *const Inner);
+    inner.subscriptions.lock().unwrap().reset();
+
+    if rc == bindings::MOSQ_ERR_SUCCESS {
+        inner.state.set(ConnectionState::Disconnected);
+    } else if !inner.auto_reconnect.load(Ordering::SeqCst) {
+        // The network thread ends instead of reconnecting when it
+        // thinks a disconnect was asked for
+        bindings::mosquitto_disconnect(mqtt);
+        inner.background.ending.store(true, Ordering::SeqCst);
+        inner.state.set(ConnectionState::Disconnected);
+    } else {
+        let policy = *inner.reconnect_policy.lock().unwrap();
+        if policy.jitter > 0 {
+            let _ = policy.jittered().set(mqtt);
+        }
+        inner.state.set(ConnectionState::Reconnecting);
+    }
+    inner.call_int("on_disconnect", rc);
+}
+
+unsafe extern "C" fn onsubscribe_wrapper(_mqtt: *mut bindings::Struct_mosquitto,
+                                         inner: *mut libc::c_void,
+                                         mid: libc::c_int,
+                                         qos_count: libc::c_int,
+                                         qos_list: *const libc::c_int) {
+    let inner = &*(inner as *const Inner);
+
+    let granted = if qos_list.is_null() || qos_count <= 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(qos_list, qos_count as usize)
+    };
+
+    let mut ack = SubscribeAck::new(mid, granted);
+    ack.filter = inner.subscriptions.lock().unwrap().acked(&ack);
+
+    if let Some(ref mut cb) = *inner.subscribe_callback.lock().unwrap() {
+        cb(ack)
+    }
+}
+
+unsafe extern "C" fn onunsubscribe_wrapper(_mqtt: *mut bindings::Struct_mosquitto,
+                                           inner: *mut libc::c_void,
+                                           mid: libc::c_int) {
+    let inner = &*(inner as *const Inner);
+    inner.call_int("on_unsubscribe", mid);
+}
+
+unsafe extern "C" fn onpublish_wrapper(_mqtt: *mut bindings::Struct_mosquitto,
+                                       inner: *mut libc::c_void,
+                                       mid: libc::c_int) {
+    let inner = &*(inner as *const Inner);
+    inner.deliveries.lock().unwrap().complete(mid);
+    inner.call_int("on_publish", mid);
+}
+
+unsafe extern "C" fn onmessage_wrapper(_mqtt: *mut bindings::Struct_mosquitto,
+                                       inner: *mut libc::c_void,
+                                       mqtt_message: *const bindings::Struct_mosquitto_message) {
+    let inner = &*(inner as *const Inner);
+    let mqtt_message = MessageRef::from_raw(mqtt_message);
+
+    if let Some(cb) = inner.mcallbacks.lock().unwrap().get("on_message") {
+        cb(&mqtt_message)
+    }
+
+    // Out of the lock first, sending to a full queue can block
+    let channel = inner.channel.lock().unwrap().clone();
+    if let Some(sender) = channel {
+        sender.send(&mqtt_message);
+    }
+
+    let routed = inner.router.lock().unwrap().dispatch(&mqtt_message);
+    if !routed {
+        if let Some(cb) = inner.mcallbacks.lock().unwrap().get("on_unrouted") {
+            cb(&mqtt_message)
+        }
+    }
+}
+
+unsafe extern "C" fn onlog_wrapper(_mqtt: *mut bindings::Struct_mosquitto,
+                                   inner: *mut libc::c_void,
+                                   level: libc::c_int,
+                                   msg: *const libc::c_char) {
+    let inner = &*(inner as *const Inner);
+    let level = logging::Level::from_mosquitto(level);
+    // libmosquitto logs every packet at debug level. Skip the lock and the
+    // conversion for what gets filtered out anyway
+    if msg.is_null() || !logging::enabled(level) {
+        return;
+    }
+
+    let msg = CStr::from_ptr(msg).to_string_lossy();
+    let id = inner.client_id.lock().unwrap();
+    logging::log(Some(&id), level, &msg);
+}
@@ -6,42 +6,237 @@
 
 
 extern crate libc;
+extern crate log;
 extern crate mosquitto_sys as bindings;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
+#[cfg(feature = "mio")]
+extern crate mio;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 
 use std::ptr;
-use std::mem;
-use std::ffi::{CString, CStr, NulError};
-use std::collections::HashMap;
+use std::io;
+use std::fmt;
+use std::ffi::{CString, CStr};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(all(unix, feature = "mio"))]
+use mio::unix::SourceFd;
+
+#[cfg(feature = "tokio")]
+mod async_client;
+mod channel;
+mod delivery;
+mod error;
+mod inner;
+mod logging;
+mod message;
+mod options;
+mod router;
+mod state;
+mod subscription;
+pub mod topic;
+
+#[cfg(feature = "tokio")]
+pub use async_client::{AckFuture, AsyncClient, MessageStream};
+pub use channel::{Messages, Overflow};
+pub use delivery::DeliveryToken;
+pub use error::Error;
+pub use message::{Message, MessageRef};
+pub use options::{ClientOptions, ProtocolVersion, ReconnectPolicy, TlsOptions, Will};
+pub use state::{ConnectionState, StateWatcher};
+pub use subscription::{SubscribeAck, SubscribeFailure, Subscription, SubscriptionState};
+pub use topic::{TopicFilter, TopicName};
+use inner::{Handle, Inner};
 
 #[macro_use]
 extern crate lazy_static;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref INSTANCES: Mutex<usize> = Mutex::new(0);
 }
 
+///Largest payload the MQTT remaining length field can describe
+const MQTT_MAX_PAYLOAD: usize = 268_435_455;
+
+///Fails with `Error::PayloadTooLarge` before libmosquitto gets to see the payload
+fn check_payload_size(payload: &[u8]) -> Result<(), Error> {
+    if payload.len() > MQTT_MAX_PAYLOAD {
+        return Err(Error::PayloadTooLarge(payload.len()));
+    }
+    Ok(())
+}
+
+///How long `run` blocks in `select` before checking for a stop request
+const RUN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 
 // #[derive(Default)]
-// #[derive(Debug)]
-pub struct MqttClient<'b, 'c, 'd> {
-    pub id: String,
-    pub user_name: Option<&'b str>,
-    pub password: Option<&'c str>,
-    pub host: Option<&'d str>,
-    pub keep_alive: i32,
-    pub clean_session: bool,
-    pub icallbacks: HashMap<String, Box<FnMut(i32)>>,
-    pub scallbacks: HashMap<String, Box<Fn(&str)>>,
+pub struct MqttClient {
+    options: ClientOptions,
+    host: Option<String>,
+    inner: Box<Inner>,
     pub mosquitto: *mut bindings::Struct_mosquitto,
 }
 
+// The handle isn't tied to the thread that created it, and everything
+// `Inner` shares with the network thread is behind a Mutex or an atomic.
+// Not Sync: `run`, `loop_once` and `reconnect` must not drive the same
+// handle from several threads at once
+unsafe impl Send for MqttClient {}
+
+///Socket handle returned by `MqttClient::socket`
+#[cfg(unix)]
+pub type RawSocket = RawFd;
+#[cfg(not(unix))]
+pub type RawSocket = libc::c_int;
+
+///Message id libmosquitto assigns to outgoing packets
+pub type Mid = i32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Qos {
     AtMostOnce,
     AtLeastOnce,
     ExactlyOnce,
 }
 
+impl Qos {
+    fn as_int(self) -> i32 {
+        match self {
+            Qos::AtMostOnce => 0,
+            Qos::AtLeastOnce => 1,
+            Qos::ExactlyOnce => 2,
+        }
+    }
+
+    fn from_int(qos: i32) -> Option<Qos> {
+        match qos {
+            0 => Some(Qos::AtMostOnce),
+            1 => Some(Qos::AtLeastOnce),
+            2 => Some(Qos::ExactlyOnce),
+            _ => None,
+        }
+    }
+}
+
+///Return code of the CONNACK packet the broker sends in response to a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectReturnCode {
+    Accepted,
+    UnacceptableProtocolVersion,
+    IdentifierRejected,
+    ServerUnavailable,
+    BadUsernameOrPassword,
+    NotAuthorized,
+    Unknown(i32),
+}
+
+impl ConnectReturnCode {
+    ///Raw CONNACK code as sent by the broker
+    pub fn code(&self) -> i32 {
+        match *self {
+            ConnectReturnCode::Accepted => 0,
+            ConnectReturnCode::UnacceptableProtocolVersion => 1,
+            ConnectReturnCode::IdentifierRejected => 2,
+            ConnectReturnCode::ServerUnavailable => 3,
+            ConnectReturnCode::BadUsernameOrPassword => 4,
+            ConnectReturnCode::NotAuthorized => 5,
+            ConnectReturnCode::Unknown(n) => n,
+        }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        *self == ConnectReturnCode::Accepted
+    }
+
+    ///Only an unavailable server is worth retrying with the same settings.
+    ///Every other refusal needs a change on the client or the broker side
+    pub fn is_temporary(&self) -> bool {
+        *self == ConnectReturnCode::ServerUnavailable
+    }
+}
+
+impl From<i32> for ConnectReturnCode {
+    fn from(rc: i32) -> ConnectReturnCode {
+        match rc {
+            0 => ConnectReturnCode::Accepted,
+            1 => ConnectReturnCode::UnacceptableProtocolVersion,
+            2 => ConnectReturnCode::IdentifierRejected,
+            3 => ConnectReturnCode::ServerUnavailable,
+            4 => ConnectReturnCode::BadUsernameOrPassword,
+            5 => ConnectReturnCode::NotAuthorized,
+            n => ConnectReturnCode::Unknown(n),
+        }
+    }
+}
+
+impl fmt::Display for ConnectReturnCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // mosquitto_connack_string returns pointers to static strings
+        let msg = unsafe { CStr::from_ptr(bindings::mosquitto_connack_string(self.code())) };
+        write!(f, "{}", msg.to_string_lossy())
+    }
+}
+
+///Why the connection to the broker went away
+#[derive(Debug)]
+pub enum DisconnectReason {
+    ///Client called `disconnect` (or was dropped)
+    Requested,
+    ///Connection was lost. Holds what libmosquitto reported
+    Unexpected(Error),
+}
+
+impl DisconnectReason {
+    fn from_rc(rc: i32) -> DisconnectReason {
+        match Error::from_rc(rc) {
+            Ok(()) => DisconnectReason::Requested,
+            Err(e) => DisconnectReason::Unexpected(e),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        match *self {
+            DisconnectReason::Requested => true,
+            DisconnectReason::Unexpected(_) => false,
+        }
+    }
+}
+
+///Ends `MqttClient::run` (and `loop_forever`) from another thread.
+///The loop notices within half a second and returns `Ok`. The connection
+///stays open
+///
+///```ignore
+///let client = ClientOptions::new("scooter-1").network_thread(false).build()?;
+///let stop = client.stop_handle();
+///thread::spawn(move || {
+///    thread::sleep(Duration::from_secs(60));
+///    stop.stop();
+///});
+///client.run().unwrap();
+///```
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
 
 fn cleanup() {
     unsafe {
@@ -49,56 +244,71 @@ fn cleanup() {
     }
 }
 
-impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
+impl MqttClient {
     ///Creates a new mosquitto mqtt client
     ///
     ///**id**: ID of the new client  
     ///**clean**: Clean session or not. If not, broker will remember this client(useful during connection drops)
     ///
+    ///Use `ClientOptions` for everything else that needs to be set
+    ///before connecting.
+    ///
     ///```ignore
     ///let mut client = Client::new(&id, true).unwrap()
     ///``
-    pub fn new(id: &str, clean: bool) -> Result<MqttClient<'b, 'c, 'd>, i32> {
-        let icallbacks: HashMap<String, Box<FnMut(i32)>> = HashMap::new();
-        let scallbacks: HashMap<String, Box<Fn(&str)>> = HashMap::new();
+    pub fn new(id: &str, clean: bool) -> Result<MqttClient, Error> {
+        ClientOptions::new(id).clean_session(clean).build()
+    }
 
+    ///Creates the mosquitto handle and applies already validated options to it
+    fn with_options(options: ClientOptions) -> Result<MqttClient, Error> {
         let mut client = MqttClient {
-            id: id.to_string(),
-            user_name: None,
-            password: None,
+            options,
             host: None,
-            keep_alive: 10,
-            clean_session: clean,
-            icallbacks: icallbacks, // integer callbacks
-            scallbacks: scallbacks, // string callbacks
+            inner: Box::new(Inner::default()),
             mosquitto: ptr::null_mut(),
         };
 
-        let id = CString::new(id);
+        let id = CString::new(client.options.id.as_str())?;
+        // libmosquitto only generates an id for a NULL one
+        let id_ptr = if client.options.id.is_empty() { ptr::null() } else { id.as_ptr() };
 
-        // TODO: Replace all 'unwrap().as_ptr() as *const _' with 'unwrap().as_ptr()' in rust 1.6
         unsafe {
-            client.mosquitto = bindings::mosquitto_new(id.unwrap().as_ptr(),
-                                                       clean as u8,
+            client.mosquitto = bindings::mosquitto_new(id_ptr,
+                                                       client.options.clean_session as u8,
                                                        ptr::null_mut());
         }
 
-        if client.mosquitto != ptr::null_mut() {
+        if !client.mosquitto.is_null() {
+            unsafe {
+                inner::register(client.mosquitto, &client.inner);
+            }
+            *client.inner.connect_timeout.handle.lock().unwrap() = Some(Handle {
+                mosq: client.mosquitto,
+                inner: &*client.inner,
+            });
 
             let mut instances = INSTANCES.lock().unwrap();
             *instances += 1;
-            println!("mosq client instance {:?} created", *instances);
+            logging::log(Some(client.id()),
+                         logging::Level::Debug,
+                         &format!("mosq client instance {:?} created", *instances));
             if *instances == 1 {
                 unsafe {
-                    println!("@@@ Initializing mosquitto library @@@");
+                    logging::log(None, logging::Level::Debug, "Initializing mosquitto library");
                     bindings::mosquitto_lib_init();
                 }
             }
+            drop(instances);
 
-
+            client.options.apply(client.mosquitto, &client.inner)?;
             Ok(client)
         } else {
-            Err(-1)
+            // mosquitto_new only fails with ENOMEM or EINVAL in errno
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENOMEM) => Err(Error::NoMem),
+                _ => Err(Error::Inval),
+            }
         }
     }
 
@@ -114,12 +324,85 @@ impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
     ///```
     ///
     pub fn keep_alive(mut self, keepalive: i32) -> Self {
-        self.keep_alive = keepalive;
+        self.options.keep_alive = keepalive;
         self
     }
 
 
 
+    ///Username and password the client authenticates with.
+    ///Needs to be set before connecting to brokers with `allow_anonymous false`
+    ///
+    ///```ignore
+    ///let mut client = MqttClient::new(&id, true)
+    ///                         .unwrap()
+    ///                         .credentials("scooter", "secret")
+    ///                         .unwrap();
+    ///```
+    ///
+    pub fn credentials(mut self, user_name: &str, password: &str) -> Result<Self, Error> {
+        let options = self.options.clone().credentials(user_name, password);
+        self.update_options(options, |o, mosq, _| o.apply_credentials(mosq))?;
+        Ok(self)
+    }
+
+    ///Validates `options`, pushes what `apply` covers into the mosquitto
+    ///handle and keeps them. Only the changed part gets applied, so that a
+    ///protocol fallback survives setting something else
+    fn update_options<F>(&mut self, options: ClientOptions, apply: F) -> Result<(), Error>
+        where F: FnOnce(&ClientOptions, *mut bindings::Struct_mosquitto, &Inner) -> Result<(), Error>
+    {
+        options.validate()?;
+        apply(&options, self.mosquitto, &self.inner)?;
+        self.options = options;
+        Ok(())
+    }
+
+    ///Client id this client was created with
+    pub fn id(&self) -> &str {
+        &self.options.id
+    }
+
+    ///Broker host passed to the last `connect`
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    ///Protocol version used for the next (re)connect. Differs from the
+    ///configured one after a fallback to MQTT 3.1
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        *self.inner.protocol_version.lock().unwrap()
+    }
+
+    ///Changes the protocol version. Takes effect on the next (re)connect and
+    ///ends an earlier fallback to MQTT 3.1
+    ///
+    ///```ignore
+    ///client.set_protocol_version(ProtocolVersion::V311).unwrap();
+    ///```
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) -> Result<(), Error> {
+        let options = self.options.clone().protocol_version(version);
+        self.update_options(options, ClientOptions::apply_protocol)
+    }
+
+    ///Replaces the reconnect policy. Takes effect on the next reconnect
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) -> Result<(), Error> {
+        let options = self.options.clone().reconnect_policy(policy);
+        self.update_options(options, ClientOptions::apply_reconnect)
+    }
+
+    ///Turns automatic reconnects on or off. See `ClientOptions::auto_reconnect`
+    pub fn set_auto_reconnect(&mut self, auto_reconnect: bool) {
+        self.options.auto_reconnect = auto_reconnect;
+        self.inner.auto_reconnect.store(auto_reconnect, Ordering::SeqCst);
+    }
+
+
+
     ///Will topic and message on behalf of the client.
     ///Broker will take the responsibility of publishing this
     ///after the client dies
@@ -128,26 +411,38 @@ impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
     ///let mut client = MqttClient::new(&id, true)
     ///                         .unwrap()
     ///                         .keep_alive(5)
-    ///                         .will("goodbye", "my last words");
+    ///                         .will("goodbye", b"my last words", Qos::ExactlyOnce, false)
+    ///                         .unwrap();
     ///```
     ///
-    pub fn will(self, topic: &str, message: &str) -> Self {
+    pub fn will<P>(mut self, topic: &str, payload: P, qos: Qos, retain: bool) -> Result<Self, Error>
+        where P: AsRef<[u8]>
+    {
+        self.set_will(topic, payload, qos, retain)?;
+        Ok(self)
+    }
 
-        let msg_len = message.len();
-        let topic = CString::new(topic);
-        let message = CString::new(message);
+    ///Sets or replaces the will. Takes effect on the next (re)connect
+    ///
+    ///```ignore
+    ///client.set_will("scooters/1/online", b"0", Qos::AtLeastOnce, true).unwrap();
+    ///```
+    pub fn set_will<P>(&mut self, topic: &str, payload: P, qos: Qos, retain: bool) -> Result<(), Error>
+        where P: AsRef<[u8]>
+    {
+        let options = self.options.clone().will(topic, payload, qos, retain);
+        self.update_options(options, |o, mosq, _| o.apply_will(mosq))
+    }
 
+    ///Removes the will. Takes effect on the next (re)connect
+    pub fn will_clear(&mut self) -> Result<(), Error> {
+        let n_ret;
         unsafe {
-            // Publish will with Qos 2
-            bindings::mosquitto_will_set(self.mosquitto,
-                                         topic.unwrap().as_ptr(),
-                                         msg_len as i32,
-                                         message.unwrap().as_ptr() as *mut libc::c_void,
-                                         2,
-                                         0);
+            n_ret = bindings::mosquitto_will_clear(self.mosquitto);
         }
-        self
-
+        Error::from_rc(n_ret)?;
+        self.options.will = None;
+        Ok(())
     }
 
 
@@ -156,49 +451,270 @@ impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
     ///
     ///```ignore
     /// match client.connect("localhost") {
-    ///     Ok(_) => println!("Connection successful --> {:?}", client.id),
-    ///     Err(n) => panic!("Connection error = {:?}", n),
+    ///     Ok(_) => println!("Connection successful --> {:?}", client.id()),
+    ///     Err(e) => panic!("Connection error = {}", e),
     /// }
     ///```
     ///
-    pub fn connect(&mut self, host: &'d str, port: i32) -> Result<&Self, i32> {
+    pub fn connect(&mut self, host: &str, port: i32) -> Result<&Self, Error> {
 
-        self.host = Some(host);
+        self.host = Some(host.to_string());
 
-        let host = CString::new(host);
+        let host = CString::new(host)?;
+        // Before connecting, CONNACK can come in as soon as the loop runs
+        self.connect_started(ConnectionState::Connecting);
 
         let n_ret;
         // Connect to broker
         // TODO: Take optional port number in the string and split it
         unsafe {
             n_ret = bindings::mosquitto_connect(self.mosquitto,
-                                                host.unwrap().as_ptr(),
+                                                host.as_ptr(),
                                                 port,
-                                                self.keep_alive);
-            if n_ret == 0 {
-                // TODO: What happens to this thread if there is a problem if error is reported in callback (n_ret == 0 and error in callback (is this possible?))
-                // Start a thread to process network traffic. All the callbacks are handled by this thread
-                // Seems like this needs to be called per client. Or else callbacks are not working.
-                bindings::mosquitto_loop_start(self.mosquitto);
-                Ok(self)
-            } else {
-                Err(n_ret)
+                                                self.options.keep_alive);
+            let mut result = Error::from_rc(n_ret);
+            // TODO: What happens to this thread if there is a problem if error is reported in callback (n_ret == 0 and error in callback (is this possible?))
+            // Start a thread to process network traffic. All the callbacks are handled by this thread
+            // Seems like this needs to be called per client. Or else callbacks are not working.
+            if result.is_ok() && self.options.network_thread {
+                result = self.start_background();
+            }
+            if let Err(e) = result {
+                self.inner.state.set(ConnectionState::Disconnected);
+                return Err(e);
             }
         }
+        self.start_connect_timer();
+        Ok(self)
+    }
+
+    ///Starts connecting to the broker and returns without waiting for the
+    ///connection. The outcome is reported to the connect callback and through
+    ///`state` and `state_watcher`: `Connected` once the broker accepted,
+    ///`Disconnected` if `ClientOptions::connect_timeout` ran out first.
+    ///
+    ///Name resolution and opening the socket happen on a thread of their
+    ///own. If that fails in a way reconnecting can't fix, the state moves to
+    ///`Disconnected` and the disconnect callback gets the error. Other
+    ///failures, like an unreachable broker, are retried by the network
+    ///thread after the reconnect delay. Dropping the client waits for a
+    ///name lookup that is still going on.
+    ///
+    ///libmosquitto only supports this together with its network thread, so
+    ///clients built with `network_thread(false)` get `Error::InvalidOption`
+    ///and have to use `connect`.
+    ///
+    ///```ignore
+    ///let mut watcher = client.state_watcher();
+    ///client.connect_async("broker.fleet.local", 1883)?;
+    ///// ... finish starting up ...
+    ///if !watcher.wait_for(ConnectionState::Connected, Duration::from_secs(30)) {
+    ///    println!("@@@ Still not connected, state = {:?}", client.state());
+    ///}
+    ///```
+    pub fn connect_async(&mut self, host: &str, port: i32) -> Result<(), Error> {
+        self.check_async_connect()?;
+        self.host = Some(host.to_string());
+
+        let host = CString::new(host)?;
+        let keep_alive = self.options.keep_alive;
+        self.connect_started(ConnectionState::Connecting);
+        self.spawn_connect(move |mosq| unsafe {
+            bindings::mosquitto_connect_async(mosq, host.as_ptr(), port, keep_alive)
+        });
+        Ok(())
+    }
+
+    pub fn reconnect(&self) -> Result<&Self, Error> {
+        self.connect_started(ConnectionState::Reconnecting);
+        let mut result = self.reconnect_raw();
+        // The thread that served the old connection ended with `disconnect`
+        if result.is_ok() && self.options.network_thread {
+            result = self.start_background();
+        }
+        if let Err(e) = result {
+            self.inner.state.set(ConnectionState::Disconnected);
+            return Err(e);
+        }
+        self.start_connect_timer();
+        Ok(self)
+    }
+
+    ///`reconnect` counterpart of `connect_async`
+    pub fn reconnect_async(&self) -> Result<(), Error> {
+        self.check_async_connect()?;
+        self.connect_started(ConnectionState::Reconnecting);
+        self.spawn_connect(|mosq| unsafe { bindings::mosquitto_reconnect_async(mosq) });
+        Ok(())
+    }
+
+    fn check_async_connect(&self) -> Result<(), Error> {
+        if !self.options.network_thread {
+            return Err(Error::InvalidOption("async connects need the network thread"));
+        }
+        Ok(())
+    }
+
+    ///State changes shared by all the ways of connecting
+    fn connect_started(&self, state: ConnectionState) {
+        // Has to happen before connecting, which would keep the thread going
+        self.join_ended_background();
+        self.inner.disconnect_requested.store(false, Ordering::SeqCst);
+        *self.inner.refused.lock().unwrap() = None;
+        let timeout = &self.inner.connect_timeout;
+        timeout.attempt.fetch_add(1, Ordering::SeqCst);
+        timeout.expired.store(false, Ordering::SeqCst);
+        self.inner.state.set(state);
+    }
+
+    ///Runs `connect` and starts the network thread on a thread of their
+    ///own, so name resolution doesn't block the caller. Failures that the
+    ///network thread can retry are left to it
+    fn spawn_connect<F>(&self, connect: F)
+        where F: FnOnce(*mut bindings::Struct_mosquitto) -> libc::c_int,
+              F: Send + 'static
+    {
+        self.start_connect_timer();
+        let shared = self.inner.connect_timeout.clone();
+        let attempt = shared.attempt.load(Ordering::SeqCst);
+        let state = self.inner.state.clone();
+
+        thread::spawn(move || {
+            // Held throughout, `Drop` waits for it before destroying the handle
+            let handle = shared.handle.lock().unwrap();
+            let (mosq, inner) = match *handle {
+                Some(Handle { mosq, inner }) => (mosq, unsafe { &*inner }),
+                None => return,
+            };
+            // A disconnect, timeout or newer connect got there first
+            if shared.attempt.load(Ordering::SeqCst) != attempt || !state.get().is_connecting() {
+                return;
+            }
+
+            // The network thread retries recoverable failures after the reconnect delay
+            let result = Error::from_rc(connect(mosq))
+                .or_else(|e| if e.is_recoverable() { Ok(()) } else { Err(e) })
+                .and_then(|()| unsafe { inner.background.start(mosq) });
+            if let Err(e) = result {
+                state.set(ConnectionState::Disconnected);
+                let id = inner.client_id.lock().unwrap().clone();
+                logging::log(Some(&id), logging::Level::Warn, &format!("connect failed: {}", e));
+                inner.call_int("on_disconnect", e.code().unwrap_or(bindings::MOSQ_ERR_UNKNOWN));
+            }
+        });
+    }
+
+    ///Gives up on the connection if the broker hasn't accepted it within
+    ///`ClientOptions::connect_timeout`. Runs on its own thread, which only
+    ///touches the handle while the client is alive
+    fn start_connect_timer(&self) {
+        let timeout = match self.options.connect_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let shared = self.inner.connect_timeout.clone();
+        let attempt = shared.attempt.load(Ordering::SeqCst);
+        let state = self.inner.state.clone();
+        let mut watcher = state.watcher();
+        let background = self.inner.background.clone();
+        let id = self.id().to_string();
+
+        thread::spawn(move || {
+            // A timeout too large to add to the current time never runs out
+            let deadline = match Instant::now().checked_add(timeout) {
+                Some(deadline) => deadline,
+                None => return,
+            };
+            while watcher.get().is_connecting() {
+                let now = Instant::now();
+                if now < deadline {
+                    watcher.changed(deadline - now);
+                    continue;
+                }
+
+                let handle = shared.handle.lock().unwrap();
+                // Only if no newer connect took over in the meantime
+                if let Some(Handle { mosq, .. }) = *handle {
+                    if shared.attempt.load(Ordering::SeqCst) == attempt && state.get().is_connecting() {
+                        shared.expired.store(true, Ordering::SeqCst);
+                        let mut running = background.running.lock().unwrap();
+                        unsafe {
+                            // Also stops the network thread from retrying
+                            bindings::mosquitto_disconnect(mosq);
+                            if *running {
+                                bindings::mosquitto_loop_stop(mosq, false as u8);
+                            }
+                        }
+                        *running = false;
+                        background.ending.store(false, Ordering::SeqCst);
+                        drop(running);
+                        state.set(ConnectionState::Disconnected);
+                        logging::log(Some(&id),
+                                     logging::Level::Warn,
+                                     &format!("broker didn't accept the connection within {:?}", timeout));
+                    }
+                }
+                return;
+            }
+        });
     }
 
-    pub fn reconnect(&self) -> Result<&Self, i32> {
+    ///`reconnect` without the state changes, for loops that keep retrying
+    fn reconnect_raw(&self) -> Result<(), Error> {
+        self.inner.disconnect_requested.store(false, Ordering::SeqCst);
 
         let n_ret;
         // Connect to broker
         unsafe {
             n_ret = bindings::mosquitto_reconnect(self.mosquitto);
-            if n_ret == 0 {
-                Ok(self)
-            } else {
-                Err(n_ret)
-            }
         }
+        Error::from_rc(n_ret)
+    }
+
+    ///Disconnects from the broker. The disconnect callback sees this
+    ///as `DisconnectReason::Requested` and no automatic reconnect happens
+    pub fn disconnect(&self) -> Result<(), Error> {
+        self.inner.disconnect_requested.store(true, Ordering::SeqCst);
+        let n_ret;
+        unsafe {
+            n_ret = bindings::mosquitto_disconnect(self.mosquitto);
+        }
+        self.inner.background.ending.store(true, Ordering::SeqCst);
+        // Otherwise the disconnect callback takes care of it
+        if n_ret == bindings::MOSQ_ERR_NO_CONN {
+            self.inner.state.set(ConnectionState::Disconnected);
+        }
+        Error::from_rc(n_ret)
+    }
+
+    ///True between a successful CONNACK and the next disconnect
+    pub fn is_connected(&self) -> bool {
+        self.state() == ConnectionState::Connected
+    }
+
+    ///Where the connection to the broker stands right now
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state.get()
+    }
+
+    ///Handle for following state changes from any thread
+    pub fn state_watcher(&self) -> StateWatcher {
+        self.inner.state.watcher()
+    }
+
+    ///Registered callback is called on every change of `state`, on the
+    ///thread that caused it
+    ///
+    ///```ignore
+    ///client.onstate_callback(move |state| {
+    ///         println!("@@@ Connection is {:?}", state);
+    ///     });
+    ///```
+    pub fn onstate_callback<F>(&mut self, callback: F)
+        where F: FnMut(ConnectionState),
+              F: Send + 'static
+    {
+        self.inner.state.set_callback(Box::new(callback));
     }
 
 
@@ -208,174 +724,173 @@ impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
     ///
     ///```ignore
     /// match client.connect("localhost") {
-    ///     Ok(_) => println!("Connection successful --> {:?}", client.id),
-    ///     Err(n) => panic!("Connection error = {:?}", n),
+    ///     Ok(_) => println!("Connection successful --> {:?}", client.id()),
+    ///     Err(e) => panic!("Connection error = {}", e),
     /// }
     ///```
     ///
     pub fn secure_connect(&mut self,
-                          host: &'d str,
+                          host: &str,
                           port: i32,
                           ca_cert: &str,
                           client_cert: Option<(&str, &str)>)
-                          -> Result<&Self, i32> {
-        // TODO: Remove all the unwraps and panics from the code
-        let c_ca_cert = CString::new(ca_cert);
-        let c_client_cert: Result<CString, NulError>;
-        let c_client_key: Result<CString, NulError>;
-
-        let tls_ret: i32;
-        match client_cert {
-            Some((cert, key)) => {
-                c_client_cert = CString::new(cert);
-                c_client_key = CString::new(key);
-                unsafe {
-                    bindings::mosquitto_tls_insecure_set(self.mosquitto, 1 as u8);
-                    tls_ret = bindings::mosquitto_tls_set(self.mosquitto,
-                                                          c_ca_cert.unwrap().as_ptr(),
-                                                          ptr::null_mut(),
-                                                          c_client_cert.unwrap().as_ptr(),
-                                                          c_client_key.unwrap().as_ptr(),
-                                                          None);
-                }
-
-                if tls_ret != 0 {
-                    cleanup();
-                    Err(tls_ret)
-                } else {
-                    self.connect(host, port)
-                }
-
-            }
-            None => {
-                unsafe {
-                    tls_ret = bindings::mosquitto_tls_set(self.mosquitto,
-                                                          c_ca_cert.unwrap().as_ptr(),
-                                                          ptr::null_mut(),
-                                                          ptr::null_mut(),
-                                                          ptr::null_mut(),
-                                                          None);
-                }
-
-                if tls_ret != 0 {
-                    cleanup();
-                    Err(tls_ret)
-                } else {
-                    self.connect(host, port)
-                }
-            }
+                          -> Result<&Self, Error> {
+        // Hostname verification has always been skipped for client certificates
+        let insecure = client_cert.is_some();
+        let mut options = self.options.clone().tls(ca_cert, client_cert);
+        if let Some(ref mut tls) = options.tls {
+            tls.insecure = insecure;
         }
+        self.update_options(options, |o, mosq, _| o.apply_tls(mosq))?;
+        self.connect(host, port)
     }
 
 
     ///Registered callback is called when the broker sends a CONNACK message in response
     ///to a connection. Will be called even incase of failure. All your sub/pub stuff
     ///should ideally be done in this callback when connection is successful
-    ///Callback argument specifies the connection state
+    ///Callback argument is the CONNACK return code sent by the broker
     ///```ignore
     /// let i = 100;
     ///
-    /// client.onconnect_callback(move |a: i32| {
+    /// client.onconnect_callback(move |rc: ConnectReturnCode| {
     ///         println!("i = {:?}", i);
-    ///         println!("@@@ On connect callback {}@@@", a)
+    ///         println!("@@@ On connect callback {}@@@", rc)
     ///     });
     ///```
-    pub fn onconnect_callback<F>(&mut self, callback: F)
-        where F: FnMut(i32),
-              F: 'static
+    pub fn onconnect_callback<F>(&mut self, mut callback: F)
+        where F: FnMut(ConnectReturnCode),
+              F: Send + 'static
     {
-        self.icallbacks.insert("on_connect".to_string(), Box::new(callback));
-        // setting client object as userdata. Setting 'callback' as userdata is buggy because by the
-        // time the actual callback is invoked, other callbacks like 'on_subscribe' callback is overwriting
-        // the userdata and wrong closure is getting invoked for on_connect callback
-        let cb = self as *const _ as *mut libc::c_void;
-        unsafe {
-            // Set our closure as user data
-            bindings::mosquitto_user_data_set(self.mosquitto, cb);
-            // Register callback
-            bindings::mosquitto_connect_callback_set(self.mosquitto, Some(onconnect_wrapper));
-        }
-
-        // Registered callback. user data is our closure
-        unsafe extern "C" fn onconnect_wrapper(mqtt: *mut bindings::Struct_mosquitto,
-                                               closure: *mut libc::c_void,
-                                               val: libc::c_int) {
-            let client: &mut MqttClient = mem::transmute(closure);
-            match client.icallbacks.get_mut("on_connect") {
-                Some(cb) => cb(val as i32),
-                _ => panic!("No callback found"),
-            }
-        }
+        let mut callbacks = self.inner.icallbacks.lock().unwrap();
+        callbacks.insert("on_connect".to_string(),
+                         Box::new(move |rc| callback(ConnectReturnCode::from(rc))));
     }
 
+    ///Registered callback is called when the connection to the broker is closed,
+    ///either because the client asked for it or because it was lost.
+    ///An unexpected disconnect is followed by automatic reconnect attempts
+    ///```ignore
+    /// client.ondisconnect_callback(move |reason: DisconnectReason| {
+    ///         if !reason.is_requested() {
+    ///             println!("@@@ Connection lost: {:?} @@@", reason)
+    ///         }
+    ///     });
+    ///```
+    pub fn ondisconnect_callback<F>(&mut self, mut callback: F)
+        where F: FnMut(DisconnectReason),
+              F: Send + 'static
+    {
+        let mut callbacks = self.inner.icallbacks.lock().unwrap();
+        callbacks.insert("on_disconnect".to_string(),
+                         Box::new(move |rc| callback(DisconnectReason::from_rc(rc))));
+    }
 
-    ///Subscibe to a topic with a Qos
+
+    ///Subscibe to a topic with a Qos. Returns the message id of the
+    ///SUBSCRIBE packet so it can be matched with its `SubscribeAck`.
+    ///Invalid filters fail with `Error::InvalidTopic` before anything is sent.
+    ///Takes a `&str` or a `&TopicFilter`
+    ///
+    ///The client remembers the subscription and makes it again after every
+    ///reconnect. Without a connection it is queued until the next one and
+    ///`None` is returned. Subscribing to the same filter again replaces the QoS
     ///
     ///```ignore
-    /// client.subscribe("hello/world", Qos::AtMostOnce);
+    /// let mid = client.subscribe("hello/world", Qos::AtMostOnce).unwrap();
     ///```
-    pub fn subscribe(&self, topic: &str, qos: Qos) {
-        let topic = CString::new(topic);
-
-        let qos = match qos {
-            Qos::AtMostOnce => 0,
-            Qos::AtLeastOnce => 1,
-            Qos::ExactlyOnce => 2,
-        };
+    pub fn subscribe(&self, topic: &str, qos: Qos) -> Result<Option<Mid>, Error> {
+        let filter = TopicFilter::new(topic)?;
+        unsafe { self.inner.subscriptions.lock().unwrap().subscribe(self.mosquitto, filter, qos) }
+    }
 
-        unsafe {
-            bindings::mosquitto_subscribe(self.mosquitto,
-                                          ptr::null_mut(),
-                                          topic.unwrap().as_ptr(),
-                                          qos);
-        }
+    ///Subscriptions the client keeps alive, and what the broker made of them
+    ///
+    ///```ignore
+    /// for sub in client.subscriptions() {
+    ///     println!("@@@ {} -> {:?}", sub.filter, sub.state);
+    /// }
+    ///```
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.inner.subscriptions.lock().unwrap().list()
     }
 
-    ///Registered callback will be called when broker responds to a subscription
+    ///Registered callback will be called when broker responds to a subscription.
+    ///The ack holds the QoS granted for each filter, or why it was refused
     ///
     ///```ignore
-    /// client.onsubscribe_callback(move |mid| {
-    ///            println!("@@@ Subscribe request received for message mid = {:?}", mid)
+    /// client.onsubscribe_callback(move |ack: SubscribeAck| {
+    ///            if ack.is_rejected() {
+    ///                println!("@@@ Subscription {} refused: {:?}", ack.mid, ack.granted)
+    ///            }
     ///        });
     ///```
     pub fn onsubscribe_callback<F>(&mut self, callback: F)
-        where F: FnMut(i32),
-              F: 'static
+        where F: FnMut(SubscribeAck),
+              F: Send + 'static
     {
-        self.icallbacks.insert("on_subscribe".to_string(), Box::new(callback));
-        let cb = self as *const _ as *mut libc::c_void;
+        *self.inner.subscribe_callback.lock().unwrap() = Some(Box::new(callback));
+    }
 
+    ///Unsubscribe from a topic. Returns the message id of the UNSUBSCRIBE
+    ///packet, which is handed to the unsubscribe callback when the broker
+    ///acknowledges it.
+    ///
+    ///The subscription is forgotten even if there is no connection to send
+    ///the UNSUBSCRIBE on (`Error::NoConn`), so it isn't made again on reconnect
+    ///
+    ///```ignore
+    /// let mid = client.unsubscribe("hello/world").unwrap();
+    ///```
+    pub fn unsubscribe(&self, topic: &str) -> Result<Mid, Error> {
+        let c_topic = topic::checked_filter(topic)?;
+        self.inner.subscriptions.lock().unwrap().unsubscribe(topic);
+
+        let mut mid: Mid = 0;
+        let n_ret;
         unsafe {
-            bindings::mosquitto_user_data_set(self.mosquitto, cb);
-            bindings::mosquitto_subscribe_callback_set(self.mosquitto, Some(onsubscribe_wrapper));
-        }
-
-        unsafe extern "C" fn onsubscribe_wrapper(mqtt: *mut bindings::Struct_mosquitto,
-                                                 closure: *mut libc::c_void,
-                                                 mid: libc::c_int,
-                                                 qos_count: libc::c_int,
-                                                 qos_list: *const ::libc::c_int) {
-            let client: &mut MqttClient = mem::transmute(closure);
-            match client.icallbacks.get_mut("on_subscribe") {
-                Some(cb) => cb(mid as i32),
-                _ => panic!("No callback found"),
-            }
+            n_ret = bindings::mosquitto_unsubscribe(self.mosquitto, &mut mid, c_topic.as_ptr());
         }
+        Error::from_rc(n_ret)?;
+        Ok(mid)
+    }
+
+    ///Registered callback will be called when broker acknowledges an unsubscribe
+    ///
+    ///```ignore
+    /// client.onunsubscribe_callback(move |mid| {
+    ///            println!("@@@ Unsubscribe acknowledged for message mid = {:?}", mid)
+    ///        });
+    ///```
+    pub fn onunsubscribe_callback<F>(&mut self, callback: F)
+        where F: FnMut(Mid),
+              F: Send + 'static
+    {
+        let mut callbacks = self.inner.icallbacks.lock().unwrap();
+        callbacks.insert("on_unsubscribe".to_string(), Box::new(callback));
     }
 
 
-    ///Publish a message with a Qos
+    ///Publish a message with a Qos. The returned token completes once the
+    ///message is delivered, see `DeliveryToken`.
+    ///
+    ///Topics with wildcards fail with `Error::InvalidTopic` before anything
+    ///is sent. Takes a `&str` or a `&TopicName`.
+    ///
+    ///With `retain` the broker keeps the message and hands it to every
+    ///new subscriber of the topic right away.
+    ///
+    ///The payload can be anything that derefs to bytes: slices, arrays,
+    ///`String`s, `Vec`s or `bytes::Bytes`. It is not copied on the rust side
     ///
     ///```ignore
-    /// let message = format!("{}...{:?} - Message {}", count, client.id, i);
-    /// client.publish("hello/world", &message, Qos::AtLeastOnce);
+    /// let message = format!("{}...{:?} - Message {}", count, client.id(), i);
+    /// let token = client.publish("hello/world", message, Qos::AtLeastOnce, false).unwrap();
+    /// token.wait(Duration::from_secs(5));
     ///```
-    pub fn publish(&self,
-                   mid: Option<&i32>,
-                   topic: &str,
-                   message: &Vec<u8>,
-                   qos: Qos)
-                   -> Result<(), i32> {
+    pub fn publish<P>(&self, topic: &str, message: P, qos: Qos, retain: bool) -> Result<DeliveryToken, Error>
+        where P: AsRef<[u8]>
+    {
 
         // CString::new(topic).unwrap().as_ptr() is wrong.
         // topic String gets destroyed and pointer is invalidated
@@ -386,39 +901,43 @@ impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
         //
 
 
+        let message = message.as_ref();
+        check_payload_size(message)?;
         let msg_len = message.len();
 
-        let topic = CString::new(topic);
-        // let message = CString::new(message);
-
-        let qos = match qos {
-            Qos::AtMostOnce => 0,
-            Qos::AtLeastOnce => 1,
-            Qos::ExactlyOnce => 2,
-        };
+        let topic = topic::checked_name(topic)?;
 
+        let mut mid: Mid = 0;
         let n_ret: i32;
 
-        let c_mid = match mid {
-            Some(m) => m as *const i32 as *mut i32,
-            None => ptr::null_mut(),
-        };
-
+        self.inner.deliveries.lock().unwrap().expect();
         unsafe {
             n_ret = bindings::mosquitto_publish(self.mosquitto,
-                                                c_mid,
-                                                topic.unwrap().as_ptr(),
+                                                &mut mid,
+                                                topic.as_ptr(),
                                                 msg_len as i32,
-                                                message.as_ptr() as *mut libc::c_void,
-                                                qos,
-                                                0);
+                                                message.as_ptr() as *const libc::c_void,
+                                                qos.as_int(),
+                                                retain as u8);
         }
 
-        if n_ret == 0 {
-            Ok(())
-        } else {
-            Err(n_ret)
+        let result = Error::from_rc(n_ret);
+        let mut deliveries = self.inner.deliveries.lock().unwrap();
+        if let Err(e) = result {
+            deliveries.cancel();
+            return Err(e);
         }
+        Ok(deliveries.track(mid))
+    }
+
+    ///Removes the retained message of `topic` from the broker by publishing
+    ///an empty retained message to it
+    ///
+    ///```ignore
+    /// client.clear_retained("vehicles/42/state").unwrap();
+    ///```
+    pub fn clear_retained(&self, topic: &str) -> Result<DeliveryToken, Error> {
+        self.publish(topic, [], Qos::AtLeastOnce, true)
     }
 
 
@@ -433,109 +952,428 @@ impl<'b, 'c, 'd> MqttClient<'b, 'c, 'd> {
     ///```
     pub fn onpublish_callback<F>(&mut self, callback: F)
         where F: FnMut(i32),
-              F: 'static
+              F: Send + 'static
     {
-        self.icallbacks.insert("on_publish".to_string(), Box::new(callback));
-        let cb = self as *const _ as *mut libc::c_void;
+        let mut callbacks = self.inner.icallbacks.lock().unwrap();
+        callbacks.insert("on_publish".to_string(), Box::new(callback));
+    }
 
-        unsafe {
-            bindings::mosquitto_user_data_set(self.mosquitto, cb);
-            bindings::mosquitto_publish_callback_set(self.mosquitto, Some(onpublish_wrapper));
-        }
 
-        unsafe extern "C" fn onpublish_wrapper(mqtt: *mut bindings::Struct_mosquitto,
-                                               closure: *mut libc::c_void,
-                                               mid: libc::c_int) {
-            let client: &mut MqttClient = mem::transmute(closure);
-            match client.icallbacks.get_mut("on_publish") {
-                Some(cb) => cb(mid as i32),
-                _ => panic!("No callback found"),
-            }
+    ///Registered callback will be called when a message is received from the broker
+    ///
+    ///The message is only borrowed for the duration of the callback.
+    ///Use `to_message` to keep an owned copy.
+    ///
+    ///```ignore
+    ///client.onmesssage_callback(move |msg| {
+    ///         println!("@@@ Topic = {}, Payload = {:?}, Count = {:?}", msg.topic(), msg.payload(), count);
+    ///     });
+    ///```
+    pub fn onmesssage_callback<F>(&mut self, callback: F)
+        where F: Fn(&MessageRef),
+              F: Send + 'static
+    {
+        let mut callbacks = self.inner.mcallbacks.lock().unwrap();
+        callbacks.insert("on_message".to_string(), Box::new(callback));
+    }
+
+    ///Returns a receiver that gets an owned copy of every message, for
+    ///consuming them on a thread of your own. The queue is unbounded.
+    ///Calling this again replaces the previous receiver, which then
+    ///reports `Disconnected` once drained.
+    ///
+    ///```ignore
+    ///let messages = client.messages();
+    ///match messages.recv_timeout(Duration::from_secs(5)) {
+    ///    Ok(msg) => println!("@@@ {} = {:?}", msg.topic, msg.payload),
+    ///    Err(e) => println!("@@@ Nothing yet: {:?}", e),
+    ///}
+    ///```
+    pub fn messages(&mut self) -> Messages {
+        self.message_channel(None, Overflow::Block)
+    }
+
+    ///Like `messages`, but holds at most `capacity` messages and applies
+    ///`overflow` when the consumer falls behind
+    ///
+    ///```ignore
+    ///let messages = client.messages_bounded(1000, Overflow::DropOldest);
+    ///```
+    pub fn messages_bounded(&mut self, capacity: usize, overflow: Overflow) -> Messages {
+        self.message_channel(Some(capacity), overflow)
+    }
+
+    fn message_channel(&mut self, capacity: Option<usize>, overflow: Overflow) -> Messages {
+        let (sender, messages) = channel::channel(capacity, overflow);
+        let old = self.inner.channel.lock().unwrap().replace(Arc::new(sender));
+        // The network thread may still hold on to it
+        if let Some(old) = old {
+            old.close();
         }
+        messages
+    }
 
+    ///Registers a handler for messages whose topic matches `filter`. Filters can
+    ///contain `+` and `#` wildcards. A message is handed to every matching handler,
+    ///in registration order, and to the message callback as well.
+    ///Routing doesn't subscribe, call `subscribe` for the filter too.
+    ///
+    ///```ignore
+    ///client.route("vehicles/+/telemetry", |msg| {
+    ///         println!("@@@ Telemetry from {}", msg.topic());
+    ///     }).unwrap();
+    ///```
+    pub fn route<F>(&mut self, filter: &str, handler: F) -> Result<(), Error>
+        where F: Fn(&MessageRef),
+              F: Send + 'static
+    {
+        self.inner.router.lock().unwrap().add(filter, Box::new(handler))
     }
 
+    ///Removes all handlers registered for `filter` with `route`.
+    ///Returns false if there were none
+    pub fn unroute(&mut self, filter: &str) -> bool {
+        self.inner.router.lock().unwrap().remove(filter)
+    }
 
-    ///Registered callback will be called when a message is received from the broker
+    ///Registered callback will be called for messages that none of the
+    ///`route` handlers matched
     ///
     ///```ignore
-    ///client.onmesssage_callback(move |s| {
-    ///         println!("@@@ Message = {:?}, Count = {:?}", s, count);
+    ///client.onunrouted_callback(move |msg| {
+    ///         println!("@@@ Nobody handles {}", msg.topic());
     ///     });
     ///```
-    pub fn onmesssage_callback<F>(&mut self, callback: F)
-        where F: Fn(&str),
-              F: 'static
+    pub fn onunrouted_callback<F>(&mut self, callback: F)
+        where F: Fn(&MessageRef),
+              F: Send + 'static
     {
-        self.scallbacks.insert("on_message".to_string(), Box::new(callback));
-        let cb = self as *const _ as *mut libc::c_void;
+        let mut callbacks = self.inner.mcallbacks.lock().unwrap();
+        callbacks.insert("on_unrouted".to_string(), Box::new(callback));
+    }
+
+    ///Resets the client to a freshly created state with a new id.
+    ///Registered callbacks and the rest of the options are kept.
+    pub fn reinitialise(&mut self, id: &str, clean: bool) -> Result<(), Error> {
+        let mut options = self.options.clone();
+        options.id = id.to_string();
+        options.clean_session = clean;
+        options.validate()?;
+
+        let c_id = CString::new(id)?;
+        let id_ptr = if id.is_empty() { ptr::null() } else { c_id.as_ptr() };
+        self.options = options;
+
+        // libmosquitto cancels the network thread itself but the
+        // bookkeeping has to know it's gone
+        {
+            let mut running = self.inner.background.running.lock().unwrap();
+            if *running {
+                unsafe {
+                    bindings::mosquitto_loop_stop(self.mosquitto, true as u8);
+                }
+            }
+            *running = false;
+            self.inner.background.ending.store(false, Ordering::SeqCst);
+        }
+
+        let n_ret;
         unsafe {
-            bindings::mosquitto_user_data_set(self.mosquitto, cb); /* Set our closure as user data */
-            bindings::mosquitto_message_callback_set(self.mosquitto, Some(onmessage_wrapper));
+            n_ret = bindings::mosquitto_reinitialise(self.mosquitto,
+                                                     id_ptr,
+                                                     clean as u8,
+                                                     ptr::null_mut());
+            // reinitialise wipes userdata and callbacks. Point them back at `inner`
+            inner::register(self.mosquitto, &self.inner);
         }
+        // The old connection is gone without a disconnect callback
+        self.inner.state.set(ConnectionState::Disconnected);
+        self.inner.subscriptions.lock().unwrap().reset();
+        Error::from_rc(n_ret)?;
+        self.options.apply(self.mosquitto, &self.inner)
+    }
 
 
-        unsafe extern "C" fn onmessage_wrapper(mqtt: *mut bindings::Struct_mosquitto, closure: *mut libc::c_void, mqtt_message: *const bindings::Struct_mosquitto_message)
-        {
+    pub fn message_retry_set(&mut self, timeout: u32) {
+        self.options.message_retry = Some(timeout);
+        unsafe {
+            bindings::mosquitto_message_retry_set(self.mosquitto, timeout);
+        }
+    }
 
-            let mqtt_message = (*mqtt_message).payload as *const libc::c_char;
-            let mqtt_message = CStr::from_ptr(mqtt_message).to_bytes();
-            let mqtt_message = std::str::from_utf8(mqtt_message).unwrap();
 
-            let client: &mut MqttClient = mem::transmute(closure);
-            match client.scallbacks.get("on_message") {
-                Some(cb) => cb(mqtt_message),
-                _ => panic!("No callback found"),
-            }
+    ///Socket of the connection to the broker, or `None` while not connected.
+    ///For clients built without a network thread, to be watched by an
+    ///external event loop. Also `None` while the network thread runs.
+    ///
+    ///libmosquitto opens a new socket on every (re)connect. Register the
+    ///new one with the event loop after reconnecting
+    pub fn socket(&self) -> Option<RawSocket> {
+        if self.check_manual_loop().is_err() {
+            return None;
+        }
+        let sock = unsafe { bindings::mosquitto_socket(self.mosquitto) };
+        if sock < 0 {
+            None
+        } else {
+            Some(sock as RawSocket)
         }
     }
 
-    pub fn reinitialise(&self, id: &str, clean: bool) {
+    ///True if there is outgoing data queued. Watch the socket for
+    ///writability and call `loop_write` while this is set
+    pub fn want_write(&self) -> bool {
+        unsafe { bindings::mosquitto_want_write(self.mosquitto) != 0 }
+    }
 
-        let id = CString::new(id);
+    ///Reads and handles whatever is available on the socket. Call when it
+    ///becomes readable. Callbacks run on the calling thread.
+    ///
+    ///`loop_read`, `loop_write` and `loop_misc` fail with `Error::Inval`
+    ///while the network thread is running
+    pub fn loop_read(&self) -> Result<(), Error> {
+        self.check_manual_loop()?;
+        // max_packets is unused by libmosquitto, 1 is the recommended value
+        Error::from_rc(unsafe { bindings::mosquitto_loop_read(self.mosquitto, 1) })
+    }
 
-        // TODO: Replace all 'unwrap().as_ptr() as *const _' with 'unwrap().as_ptr()' in rust 1.6
-        unsafe {
-            bindings::mosquitto_reinitialise(self.mosquitto,
-                                             id.unwrap().as_ptr(),
-                                             clean as u8,
-                                             ptr::null_mut());
-        }
+    ///Writes queued data. Call when the socket becomes writable
+    pub fn loop_write(&self) -> Result<(), Error> {
+        self.check_manual_loop()?;
+        Error::from_rc(unsafe { bindings::mosquitto_loop_write(self.mosquitto, 1) })
+    }
 
+    ///Keep alive pings and QoS retries. Call about once a second
+    pub fn loop_misc(&self) -> Result<(), Error> {
+        self.check_manual_loop()?;
+        Error::from_rc(unsafe { bindings::mosquitto_loop_misc(self.mosquitto) })
     }
 
+    ///Waits up to `timeout` for network traffic and handles it, along with
+    ///keep alive and retries. Callbacks run on the calling thread.
+    ///Doesn't reconnect, a lost connection shows up as an error.
+    ///Fails with `Error::Inval` while the network thread is running
+    pub fn loop_once(&self, timeout: Duration) -> Result<(), Error> {
+        self.check_manual_loop()?;
+        let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        Error::from_rc(unsafe { bindings::mosquitto_loop(self.mosquitto, millis, 1) })
+    }
 
-    pub fn message_retry_set(&self, timeout: u32) {
-        unsafe {
-            bindings::mosquitto_message_retry_set(self.mosquitto, timeout);
+    ///Runs the network loop on the calling thread. Lost connections are
+    ///reestablished after the configured reconnect delay.
+    ///
+    ///Returns `Ok` after `disconnect` or when a `StopHandle` asks it to,
+    ///and the error for failures that reconnecting can't fix
+    ///(authentication, protocol, TLS ...). A broker refusing the connection
+    ///for anything but being unavailable ends it with `Error::Refused`
+    ///
+    ///Meant for clients built with `network_thread(false)`. Fails with
+    ///`Error::Inval` right away while the network thread is running
+    pub fn run(&self) -> Result<(), Error> {
+        self.check_manual_loop()?;
+        let base_policy = self.options.reconnect_policy.unwrap_or_default();
+        let mut policy = base_policy;
+        let mut attempt = 0;
+
+        let result = loop {
+            if self.inner.stop.load(Ordering::SeqCst) {
+                break Ok(());
+            }
+
+            let err = match self.loop_once(RUN_POLL_INTERVAL) {
+                Ok(()) => {
+                    if self.is_connected() {
+                        attempt = 0;
+                    }
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            if self.inner.disconnect_requested.load(Ordering::SeqCst) {
+                break Ok(());
+            }
+            if self.inner.connect_timeout.expired.load(Ordering::SeqCst) {
+                break Err(Error::ConnectTimeout);
+            }
+            if !err.is_recoverable() || !self.options.auto_reconnect {
+                break Err(err);
+            }
+            // Refused by the broker, which libmosquitto reports as recoverable
+            if let Some(rc) = *self.inner.refused.lock().unwrap() {
+                if !rc.is_temporary() {
+                    break Err(Error::Refused(rc));
+                }
+            }
+            if attempt == 0 {
+                policy = base_policy.jittered();
+            }
+
+            logging::log(Some(self.id()),
+                         logging::Level::Debug,
+                         &format!("network loop: {}. Reconnecting", err));
+            if !self.sleep_unless_stopped(policy.for_attempt(attempt)) {
+                break Ok(());
+            }
+            attempt = attempt.saturating_add(1);
+            self.inner.state.set(ConnectionState::Reconnecting);
+            // A failed attempt surfaces again from the next loop_once, but
+            // only as NoConn
+            if let Err(e) = self.reconnect_raw() {
+                if !e.is_recoverable() {
+                    break Err(e);
+                }
+            }
+        };
+
+        // Leave the handle ready for the next run
+        self.inner.stop.store(false, Ordering::SeqCst);
+        result
+    }
+
+    ///Returns false if a stop was requested while sleeping
+    fn sleep_unless_stopped(&self, duration: Duration) -> bool {
+        // A delay too large to add to the current time only ends with a stop
+        let deadline = Instant::now().checked_add(duration);
+        loop {
+            if self.inner.stop.load(Ordering::SeqCst) {
+                return false;
+            }
+            let left = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return true;
+                    }
+                    deadline - now
+                }
+                None => RUN_POLL_INTERVAL,
+            };
+            thread::sleep(left.min(RUN_POLL_INTERVAL));
         }
     }
 
+    ///Handle for ending `run` from another thread
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle { stop: self.inner.stop.clone() }
+    }
 
+    ///Like `run`, but only logs the error that ended the loop, including
+    ///the one for a running network thread
     pub fn loop_forever(&self) {
-        unsafe {
-            bindings::mosquitto_loop_forever(self.mosquitto, 2000, 1);
+        if let Err(e) = self.run() {
+            logging::log(Some(self.id()),
+                         logging::Level::Error,
+                         &format!("network loop stopped: {}", e));
+        }
+    }
+
+    ///Starts libmosquitto's network thread. `connect` does this already
+    ///unless the client was built with `network_thread(false)`. Does
+    ///nothing if the thread is running already
+    pub fn start_background(&self) -> Result<(), Error> {
+        self.join_ended_background();
+        unsafe { self.inner.background.start(self.mosquitto) }
+    }
+
+    ///Stops the network thread. Without `force` this waits for the thread
+    ///to finish on its own, which it only does after `disconnect`
+    pub fn stop_background(&self, force: bool) -> Result<(), Error> {
+        let mut running = self.inner.background.running.lock().unwrap();
+        Error::from_rc(unsafe { bindings::mosquitto_loop_stop(self.mosquitto, force as u8) })?;
+        *running = false;
+        self.inner.background.ending.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    ///`Error::Inval` while the network thread drives the handle, which
+    ///calling into the loop from here would race with
+    fn check_manual_loop(&self) -> Result<(), Error> {
+        self.join_ended_background();
+        if *self.inner.background.running.lock().unwrap() {
+            return Err(Error::Inval);
+        }
+        Ok(())
+    }
+
+    ///Joins a network thread that a disconnect made end, so `loop_start`
+    ///accepts a new one. libmosquitto refuses a second thread until then
+    fn join_ended_background(&self) {
+        let mut running = self.inner.background.running.lock().unwrap();
+        if self.inner.background.ending.swap(false, Ordering::SeqCst) && *running {
+            unsafe {
+                bindings::mosquitto_loop_stop(self.mosquitto, false as u8);
+            }
+            *running = false;
+        }
+    }
+}
+
+
+impl fmt::Debug for MqttClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // ClientOptions takes care of hiding the password
+        f.debug_struct("MqttClient")
+         .field("options", &self.options)
+         .field("host", &self.host)
+         .finish()
+    }
+}
+
+///Registers the current socket, see `MqttClient::socket`. Fails with
+///`io::ErrorKind::NotConnected` while there is none
+#[cfg(all(unix, feature = "mio"))]
+impl mio::event::Source for MqttClient {
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> io::Result<()> {
+        SourceFd(&connected_socket(self)?).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) -> io::Result<()> {
+        SourceFd(&connected_socket(self)?).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        match self.socket() {
+            Some(sock) => SourceFd(&sock).deregister(registry),
+            // Closing the socket already took it out of the registry
+            None => Ok(()),
         }
     }
 }
 
+#[cfg(all(unix, feature = "mio"))]
+fn connected_socket(client: &MqttClient) -> io::Result<RawFd> {
+    client.socket()
+          .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "client has no socket to register"))
+}
 
-impl<'b, 'c, 'd> Drop for MqttClient<'b, 'c, 'd> {
+impl Drop for MqttClient {
     fn drop(&mut self) {
+        // Waits for a connect timer or async connect that is using the handle right now
+        self.inner.connect_timeout.handle.lock().unwrap().take();
+        // A network thread blocked on a full queue can't be cancelled
+        if let Some(sender) = self.inner.channel.lock().unwrap().take() {
+            sender.close();
+        }
 
         unsafe {
             bindings::mosquitto_disconnect(self.mosquitto);
             bindings::mosquitto_loop_stop(self.mosquitto, true as u8);
+        }
+        self.inner.state.set(ConnectionState::Closed);
+        unsafe {
             bindings::mosquitto_destroy(self.mosquitto);
         }
 
         let mut instances = INSTANCES.lock().unwrap();
-        println!("mosq client instance {:?} desroyed", *instances);
+        logging::log(Some(self.id()),
+                     logging::Level::Debug,
+                     &format!("mosq client instance {:?} destroyed", *instances));
         *instances -= 1;
 
 
         if *instances == 0 {
-            println!("@@@ All clients dead. Cleaning mosquitto library @@@");
+            logging::log(None, logging::Level::Debug, "All clients dead. Cleaning mosquitto library");
             cleanup();
         }
     }
@@ -0,0 +1,88 @@
+//! Routes libmosquitto's log callback and the crate's own diagnostics to
+//! the `log` crate, or to `tracing` when the `tracing` feature is enabled.
+//!
+//! With `log` every client logs under its own target, `mosquitto::<client id>`,
+//! so a single noisy client can be filtered with e.g. `RUST_LOG=mosquitto::scooter-1=debug`.
+//! `tracing` needs static targets, so there all events use the `mosquitto`
+//! target and carry the client id in a `client` field.
+
+use bindings;
+use libc;
+
+///Target for messages that don't belong to a single client
+const TARGET: &str = "mosquitto";
+
+///Log level of the crate, independent of the backend in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    ///Maps `MOSQ_LOG_*` levels
+    pub fn from_mosquitto(level: libc::c_int) -> Level {
+        match level {
+            bindings::MOSQ_LOG_ERR => Level::Error,
+            bindings::MOSQ_LOG_WARNING => Level::Warn,
+            bindings::MOSQ_LOG_NOTICE | bindings::MOSQ_LOG_INFO => Level::Info,
+            bindings::MOSQ_LOG_DEBUG | bindings::MOSQ_LOG_SUBSCRIBE | bindings::MOSQ_LOG_UNSUBSCRIBE => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+///Whether messages at `level` can get through at all. Cheap enough to
+///check before doing any work for a message
+#[cfg(not(feature = "tracing"))]
+pub fn enabled(level: Level) -> bool {
+    log_level(level) <= ::log::max_level()
+}
+
+#[cfg(feature = "tracing")]
+pub fn enabled(level: Level) -> bool {
+    match level {
+        Level::Error => ::tracing::enabled!(target: TARGET, ::tracing::Level::ERROR),
+        Level::Warn => ::tracing::enabled!(target: TARGET, ::tracing::Level::WARN),
+        Level::Info => ::tracing::enabled!(target: TARGET, ::tracing::Level::INFO),
+        Level::Debug => ::tracing::enabled!(target: TARGET, ::tracing::Level::DEBUG),
+        Level::Trace => ::tracing::enabled!(target: TARGET, ::tracing::Level::TRACE),
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+fn log_level(level: Level) -> ::log::Level {
+    match level {
+        Level::Error => ::log::Level::Error,
+        Level::Warn => ::log::Level::Warn,
+        Level::Info => ::log::Level::Info,
+        Level::Debug => ::log::Level::Debug,
+        Level::Trace => ::log::Level::Trace,
+    }
+}
+
+///Logs `msg` on behalf of the client with id `client`, or of the
+///library as a whole when there is no client
+#[cfg(not(feature = "tracing"))]
+pub fn log(client: Option<&str>, level: Level, msg: &str) {
+    let target = match client {
+        Some(id) => format!("{}::{}", TARGET, id),
+        None => TARGET.to_string(),
+    };
+    ::log::log!(target: &target, log_level(level), "{}", msg);
+}
+
+#[cfg(feature = "tracing")]
+pub fn log(client: Option<&str>, level: Level, msg: &str) {
+    let client = client.unwrap_or("");
+    match level {
+        Level::Error => ::tracing::error!(target: TARGET, client, "{}", msg),
+        Level::Warn => ::tracing::warn!(target: TARGET, client, "{}", msg),
+        Level::Info => ::tracing::info!(target: TARGET, client, "{}", msg),
+        Level::Debug => ::tracing::debug!(target: TARGET, client, "{}", msg),
+        Level::Trace => ::tracing::trace!(target: TARGET, client, "{}", msg),
+    }
+}
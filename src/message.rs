@@ -0,0 +1,124 @@
+//! Messages received from the broker
+
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::slice;
+
+use bindings;
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use libc;
+use Qos;
+
+///A message received from the broker. Owns its topic and payload so it
+///can outlive the callback it was delivered in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+    ///Delivered from the broker's retained store, see `MessageRef::retain`
+    pub retain: bool,
+    pub mid: i32,
+}
+
+///Zero copy view of a message that libmosquitto hands to the message
+///callback. Only valid for the duration of the callback. Use `to_message`
+///to keep it around.
+pub struct MessageRef<'a> {
+    raw: &'a bindings::Struct_mosquitto_message,
+}
+
+impl<'a> MessageRef<'a> {
+    ///Wraps a message owned by libmosquitto.
+    ///
+    ///The pointer must be valid and unchanged for `'a`
+    pub(crate) unsafe fn from_raw(raw: *const bindings::Struct_mosquitto_message) -> MessageRef<'a> {
+        MessageRef { raw: &*raw }
+    }
+
+    pub(crate) fn raw_topic(&self) -> *const libc::c_char {
+        self.raw.topic
+    }
+
+    ///Topic the message was published on. Borrowed unless the broker
+    ///sent a topic that is not valid UTF-8
+    pub fn topic(&self) -> Cow<'a, str> {
+        if self.raw.topic.is_null() {
+            return Cow::Borrowed("");
+        }
+        unsafe { CStr::from_ptr(self.raw.topic).to_string_lossy() }
+    }
+
+    ///Raw payload bytes. `payloadlen` is used for the length, the payload
+    ///is not expected to be NUL terminated
+    pub fn payload(&self) -> &'a [u8] {
+        if self.raw.payload.is_null() || self.raw.payloadlen <= 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.raw.payload as *const u8, self.raw.payloadlen as usize) }
+    }
+
+    pub fn qos(&self) -> Qos {
+        Qos::from_int(self.raw.qos).unwrap_or(Qos::AtMostOnce)
+    }
+
+    ///True if the broker delivered a stored retained message, e.g. right
+    ///after subscribing, rather than a live publish
+    pub fn retain(&self) -> bool {
+        self.raw.retain != 0
+    }
+
+    pub fn mid(&self) -> i32 {
+        self.raw.mid
+    }
+
+    ///Copies the message out of libmosquitto's buffers
+    pub fn to_message(&self) -> Message {
+        Message {
+            topic: self.topic().into_owned(),
+            payload: self.payload().to_vec(),
+            qos: self.qos(),
+            retain: self.retain(),
+            mid: self.mid(),
+        }
+    }
+}
+
+impl Message {
+    ///Takes the payload out as `Bytes` without copying it
+    #[cfg(feature = "bytes")]
+    pub fn into_bytes(self) -> Bytes {
+        Bytes::from(self.payload)
+    }
+}
+
+impl<'a, 'b> From<&'b MessageRef<'a>> for Message {
+    fn from(msg: &'b MessageRef<'a>) -> Message {
+        msg.to_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn binary_payload() {
+        let topic = CString::new("scooters/1/raw").unwrap();
+        // Not NUL terminated and with a NUL in the middle
+        let mut payload = [0xffu8, 0x00, 0x80];
+        let raw = bindings::Struct_mosquitto_message {
+            mid: 1,
+            topic: topic.as_ptr() as *mut _,
+            payload: payload.as_mut_ptr() as *mut libc::c_void,
+            payloadlen: 3,
+            qos: 1,
+            retain: 0,
+        };
+        let msg = unsafe { MessageRef::from_raw(&raw) };
+        assert_eq!(msg.payload(), &[0xff, 0x00, 0x80]);
+        assert_eq!(msg.to_message().payload, vec![0xff, 0x00, 0x80]);
+    }
+}
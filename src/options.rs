@@ -0,0 +1,498 @@
+//! Owned client configuration
+//!
+//! `ClientOptions` collects everything the client needs before it connects.
+//! `build` validates the whole set, creates the mosquitto handle and applies
+//! the options to it.
+//!
+//!```ignore
+//!let client = ClientOptions::new("scooter-1")
+//!                 .clean_session(false)
+//!                 .keep_alive(30)
+//!                 .credentials("scooter", "secret")
+//!                 .will("goodbye", b"my last words", Qos::ExactlyOnce, false)
+//!                 .protocol_version(ProtocolVersion::V311)
+//!                 .build()
+//!                 .unwrap();
+//!```
+
+use std::collections::hash_map::RandomState;
+use std::ffi::CString;
+use std::hash::{BuildHasher, Hasher};
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use bindings;
+use inner::Inner;
+use libc;
+use topic;
+use {check_payload_size, Error, MqttClient, Qos};
+
+///Longest client id MQTT 3.1 brokers have to accept
+const MQTT_V31_ID_MAX_LENGTH: usize = 23;
+
+///MQTT protocol version spoken on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V31,
+    V311,
+}
+
+impl ProtocolVersion {
+    pub(crate) fn as_int(self) -> libc::c_int {
+        match self {
+            ProtocolVersion::V31 => 3,
+            ProtocolVersion::V311 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsOptions {
+    pub ca_file: String,
+    pub client_cert: Option<(String, String)>,
+    ///Skip verification of the broker hostname in its certificate
+    pub insecure: bool,
+}
+
+///How lost connections are reestablished. All values are seconds.
+///
+///Without `exponential` the wait grows linearly with the number of failed
+///attempts (`initial`, 2 * `initial`, ...), with it quadratically
+///(`initial`, 4 * `initial`, 9 * `initial`, ...). Either way it never
+///goes above `max`. libmosquitto's default is a fixed 1 second.
+///
+///```ignore
+///let policy = ReconnectPolicy { initial: 2, max: 120, exponential: true, jitter: 5 };
+///```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial: u32,
+    pub max: u32,
+    pub exponential: bool,
+    ///Up to this many seconds are added at random to `initial` every time
+    ///the connection is lost, so a fleet of clients doesn't reconnect in
+    ///lockstep after a broker restart
+    pub jitter: u32,
+}
+
+impl ReconnectPolicy {
+    ///Wait before reconnect attempt `attempt` (0 based), computed the way
+    ///libmosquitto's own network loops do it
+    pub(crate) fn for_attempt(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        if self.max > self.initial {
+            let n = attempt.saturating_add(1);
+            delay = if self.exponential {
+                self.initial.saturating_mul(n).saturating_mul(n)
+            } else {
+                self.initial.saturating_mul(n)
+            };
+            delay = delay.min(self.max);
+        }
+        Duration::from_secs(u64::from(delay))
+    }
+
+    ///The policy for one outage, with the random part of the jitter
+    ///folded into `initial`
+    pub(crate) fn jittered(&self) -> ReconnectPolicy {
+        if self.jitter == 0 {
+            return *self;
+        }
+        let extra = (random() % (u64::from(self.jitter) + 1)) as u32;
+        let initial = self.initial.saturating_add(extra);
+        ReconnectPolicy {
+            initial,
+            max: self.max.max(initial),
+            ..*self
+        }
+    }
+
+    ///Hands the policy, minus the jitter, to libmosquitto's network thread
+    pub(crate) unsafe fn set(&self, mosq: *mut bindings::Struct_mosquitto) -> Result<(), Error> {
+        Error::from_rc(bindings::mosquitto_reconnect_delay_set(mosq, self.initial, self.max, self.exponential as u8))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial: 1,
+            max: 1,
+            exponential: false,
+            jitter: 0,
+        }
+    }
+}
+
+///Good enough randomness for spreading out reconnects, without pulling in
+///a random number crate. Every `RandomState` is seeded differently
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[derive(Clone)]
+pub struct ClientOptions {
+    pub(crate) id: String,
+    pub(crate) clean_session: bool,
+    pub(crate) keep_alive: i32,
+    pub(crate) user_name: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) will: Option<Will>,
+    pub(crate) protocol_version: ProtocolVersion,
+    pub(crate) protocol_fallback: bool,
+    pub(crate) reconnect_policy: Option<ReconnectPolicy>,
+    pub(crate) auto_reconnect: bool,
+    pub(crate) max_inflight: Option<u32>,
+    pub(crate) message_retry: Option<u32>,
+    pub(crate) tls: Option<TlsOptions>,
+    pub(crate) network_thread: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+}
+
+impl ClientOptions {
+    ///Default options for a client with the given id. Clean session,
+    ///10 second keep alive and MQTT 3.1
+    pub fn new(id: &str) -> ClientOptions {
+        ClientOptions {
+            id: id.to_string(),
+            clean_session: true,
+            keep_alive: 10,
+            user_name: None,
+            password: None,
+            will: None,
+            protocol_version: ProtocolVersion::V31,
+            protocol_fallback: false,
+            reconnect_policy: None,
+            auto_reconnect: true,
+            max_inflight: None,
+            message_retry: None,
+            tls: None,
+            network_thread: true,
+            connect_timeout: None,
+        }
+    }
+
+    ///Clean session or not. If not, broker will remember this client(useful during connection drops)
+    pub fn clean_session(mut self, clean: bool) -> Self {
+        self.clean_session = clean;
+        self
+    }
+
+    ///Seconds after which the broker should send a PING if nothing
+    ///else has been exchanged
+    pub fn keep_alive(mut self, keep_alive: i32) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn credentials(mut self, user_name: &str, password: &str) -> Self {
+        self.user_name = Some(user_name.to_string());
+        self.password = Some(password.to_string());
+        self
+    }
+
+    ///Will topic and message published by the broker after the client dies.
+    ///Use a retained will to keep track of device presence
+    pub fn will<P>(mut self, topic: &str, payload: P, qos: Qos, retain: bool) -> Self
+        where P: AsRef<[u8]>
+    {
+        self.will = Some(Will {
+            topic: topic.to_string(),
+            payload: payload.as_ref().to_vec(),
+            qos,
+            retain,
+        });
+        self
+    }
+
+    pub fn protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    ///Retry with MQTT 3.1 when a 3.1.1 connection is refused with
+    ///"unacceptable protocol version". For brokers that only speak 3.1.
+    ///
+    ///The connect callback still sees the refusal. The retry happens on the
+    ///next automatic reconnect of the network thread.
+    pub fn protocol_fallback(mut self, fallback: bool) -> Self {
+        self.protocol_fallback = fallback;
+        self
+    }
+
+    ///How long to wait between automatic reconnect attempts
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    ///Reconnect automatically after the connection is lost (the default).
+    ///When off, the network thread and `run` end with the connection
+    pub fn auto_reconnect(mut self, auto_reconnect: bool) -> Self {
+        self.auto_reconnect = auto_reconnect;
+        self
+    }
+
+    ///Number of QoS 1 and 2 messages that can be in flight at once. 0 means no limit
+    pub fn max_inflight(mut self, max_inflight: u32) -> Self {
+        self.max_inflight = Some(max_inflight);
+        self
+    }
+
+    ///Seconds to wait for a response before retrying QoS 1 and 2 messages
+    pub fn message_retry(mut self, timeout: u32) -> Self {
+        self.message_retry = Some(timeout);
+        self
+    }
+
+    ///Certificate based TLS. `client_cert` is a (certificate, key) pair
+    pub fn tls(mut self, ca_file: &str, client_cert: Option<(&str, &str)>) -> Self {
+        self.tls = Some(TlsOptions {
+            ca_file: ca_file.to_string(),
+            client_cert: client_cert.map(|(cert, key)| (cert.to_string(), key.to_string())),
+            insecure: false,
+        });
+        self
+    }
+
+    ///Whether `connect` starts a network thread for the client (the default).
+    ///Without it the application drives the connection itself through
+    ///`socket`, `want_write`, `loop_read`, `loop_write` and `loop_misc`, and
+    ///reconnects on its own after the connection is lost
+    pub fn network_thread(mut self, network_thread: bool) -> Self {
+        self.network_thread = network_thread;
+        self
+    }
+
+    ///How long `connect` and `connect_async` wait for the broker to accept
+    ///the connection before giving up on it. The client is then disconnected,
+    ///the state moves to `Disconnected` and `run` returns
+    ///`Error::ConnectTimeout`. No timeout by default
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn user_name(&self) -> Option<&str> {
+        self.user_name.as_deref()
+    }
+
+    ///Validates the options and creates a client with them applied
+    pub fn build(self) -> Result<MqttClient, Error> {
+        self.validate()?;
+        MqttClient::with_options(self)
+    }
+
+    ///Checks the options for mistakes that libmosquitto would only
+    ///report as a bare `MOSQ_ERR_INVAL`, or not at all
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.id.is_empty() && !self.clean_session {
+            return Err(Error::InvalidOption("client id is required when clean session is off"));
+        }
+        if self.protocol_version == ProtocolVersion::V31 &&
+           self.id.len() > MQTT_V31_ID_MAX_LENGTH {
+            return Err(Error::InvalidOption("MQTT 3.1 client ids can't be longer than 23 bytes"));
+        }
+        if self.keep_alive < 0 {
+            return Err(Error::InvalidOption("keep alive can't be negative"));
+        }
+        if self.password.is_some() && self.user_name.is_none() {
+            return Err(Error::InvalidOption("password given without a user name"));
+        }
+        if let Some(ref will) = self.will {
+            topic::checked_name(&will.topic)?;
+            check_payload_size(&will.payload)?;
+        }
+        if let Some(ref policy) = self.reconnect_policy {
+            if policy.initial == 0 || policy.max < policy.initial {
+                return Err(Error::InvalidOption("reconnect delay has to be between 1 and max"));
+            }
+        }
+        if self.connect_timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidOption("connect timeout can't be zero"));
+        }
+        if let Some(ref tls) = self.tls {
+            if tls.ca_file.is_empty() {
+                return Err(Error::InvalidOption("TLS needs a CA certificate"));
+            }
+        }
+        Ok(())
+    }
+
+    ///Pushes everything except keep alive (which is only used at connect time)
+    ///into the mosquitto handle
+    pub(crate) fn apply(&self, mosq: *mut bindings::Struct_mosquitto, inner: &Inner) -> Result<(), Error> {
+        *inner.client_id.lock().unwrap() = self.id.clone();
+        self.apply_protocol(mosq, inner)?;
+        self.apply_will(mosq)?;
+        self.apply_credentials(mosq)?;
+        self.apply_tls(mosq)?;
+        self.apply_reconnect(mosq, inner)?;
+
+        if let Some(max_inflight) = self.max_inflight {
+            unsafe {
+                Error::from_rc(bindings::mosquitto_max_inflight_messages_set(mosq, max_inflight))?;
+            }
+        }
+
+        if let Some(timeout) = self.message_retry {
+            unsafe {
+                bindings::mosquitto_message_retry_set(mosq, timeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Also undoes a fallback to MQTT 3.1
+    pub(crate) fn apply_protocol(&self, mosq: *mut bindings::Struct_mosquitto, inner: &Inner) -> Result<(), Error> {
+        unsafe {
+            set_protocol_version(mosq, self.protocol_version)?;
+        }
+        *inner.protocol_version.lock().unwrap() = self.protocol_version;
+        inner.protocol_fallback.store(self.protocol_fallback, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub(crate) fn apply_will(&self, mosq: *mut bindings::Struct_mosquitto) -> Result<(), Error> {
+        if let Some(ref will) = self.will {
+            let topic = CString::new(will.topic.as_str())?;
+            unsafe {
+                Error::from_rc(bindings::mosquitto_will_set(mosq,
+                                                            topic.as_ptr(),
+                                                            will.payload.len() as libc::c_int,
+                                                            will.payload.as_ptr() as *const libc::c_void,
+                                                            will.qos.as_int(),
+                                                            will.retain as u8))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_credentials(&self, mosq: *mut bindings::Struct_mosquitto) -> Result<(), Error> {
+        if let Some(ref user_name) = self.user_name {
+            let user_name = CString::new(user_name.as_str())?;
+            let password = match self.password {
+                Some(ref password) => Some(CString::new(password.as_str())?),
+                None => None,
+            };
+            unsafe {
+                Error::from_rc(bindings::mosquitto_username_pw_set(mosq,
+                                                                   user_name.as_ptr(),
+                                                                   password.as_ref()
+                                                                           .map_or(ptr::null(), |p| p.as_ptr())))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_tls(&self, mosq: *mut bindings::Struct_mosquitto) -> Result<(), Error> {
+        if let Some(ref tls) = self.tls {
+            let ca_file = CString::new(tls.ca_file.as_str())?;
+            let (cert, key) = match tls.client_cert {
+                Some((ref cert, ref key)) => {
+                    (Some(CString::new(cert.as_str())?), Some(CString::new(key.as_str())?))
+                }
+                None => (None, None),
+            };
+            unsafe {
+                Error::from_rc(bindings::mosquitto_tls_insecure_set(mosq, tls.insecure as u8))?;
+                Error::from_rc(bindings::mosquitto_tls_set(mosq,
+                                                           ca_file.as_ptr(),
+                                                           ptr::null(),
+                                                           cert.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+                                                           key.as_ref().map_or(ptr::null(), |k| k.as_ptr()),
+                                                           None))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_reconnect(&self, mosq: *mut bindings::Struct_mosquitto, inner: &Inner) -> Result<(), Error> {
+        if let Some(ref policy) = self.reconnect_policy {
+            unsafe {
+                policy.set(mosq)?;
+            }
+        }
+        *inner.reconnect_policy.lock().unwrap() = self.reconnect_policy.unwrap_or_default();
+        inner.auto_reconnect.store(self.auto_reconnect, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+pub(crate) unsafe fn set_protocol_version(mosq: *mut bindings::Struct_mosquitto,
+                                          version: ProtocolVersion)
+                                          -> Result<(), Error> {
+    let mut version = version.as_int();
+    Error::from_rc(bindings::mosquitto_opts_set(mosq,
+                                                bindings::MOSQ_OPT_PROTOCOL_VERSION,
+                                                &mut version as *mut libc::c_int as *mut libc::c_void))
+}
+
+
+impl fmt::Debug for ClientOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the password
+        f.debug_struct("ClientOptions")
+         .field("id", &self.id)
+         .field("clean_session", &self.clean_session)
+         .field("keep_alive", &self.keep_alive)
+         .field("user_name", &self.user_name)
+         .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+         .field("will", &self.will)
+         .field("protocol_version", &self.protocol_version)
+         .field("protocol_fallback", &self.protocol_fallback)
+         .field("reconnect_policy", &self.reconnect_policy)
+         .field("auto_reconnect", &self.auto_reconnect)
+         .field("max_inflight", &self.max_inflight)
+         .field("message_retry", &self.message_retry)
+         .field("tls", &self.tls)
+         .field("network_thread", &self.network_thread)
+         .field("connect_timeout", &self.connect_timeout)
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_capped_at_max() {
+        let linear = ReconnectPolicy { initial: 2, max: 7, exponential: false, jitter: 0 };
+        assert_eq!(linear.for_attempt(0), Duration::from_secs(2));
+        assert_eq!(linear.for_attempt(2), Duration::from_secs(6));
+        assert_eq!(linear.for_attempt(3), Duration::from_secs(7));
+
+        let exponential = ReconnectPolicy { exponential: true, max: 100, ..linear };
+        assert_eq!(exponential.for_attempt(2), Duration::from_secs(18));
+        assert_eq!(exponential.for_attempt(u32::MAX), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let policy = ReconnectPolicy { initial: 2, max: 3, exponential: false, jitter: 5 };
+        for _ in 0..100 {
+            let jittered = policy.jittered();
+            assert!(jittered.initial >= 2 && jittered.initial <= 7);
+            assert!(jittered.max >= jittered.initial);
+            assert_eq!(jittered.jitter, 5);
+        }
+        let steady = ReconnectPolicy { jitter: 0, ..policy };
+        assert_eq!(steady.jittered(), steady);
+    }
+}
@@ -0,0 +1,52 @@
+//! Dispatches received messages to handlers registered per topic filter
+
+use inner::MessageCallback;
+use message::MessageRef;
+use topic::TopicFilter;
+use Error;
+
+struct Route {
+    filter: TopicFilter,
+    handler: MessageCallback,
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn add(&mut self, filter: &str, handler: MessageCallback) -> Result<(), Error> {
+        self.routes.push(Route {
+            filter: TopicFilter::new(filter)?,
+            handler,
+        });
+        Ok(())
+    }
+
+    ///Removes all handlers registered for exactly this filter.
+    ///Returns false if there were none
+    pub fn remove(&mut self, filter: &str) -> bool {
+        let before = self.routes.len();
+        self.routes.retain(|r| r.filter.as_str() != filter);
+        self.routes.len() != before
+    }
+
+    ///Calls every handler whose filter matches the message topic, in the
+    ///order they were registered. Returns false if none matched
+    pub fn dispatch(&self, message: &MessageRef) -> bool {
+        let topic = message.raw_topic();
+        if topic.is_null() {
+            return false;
+        }
+
+        let mut matched = false;
+        for route in &self.routes {
+            if route.filter.matches_raw(topic) {
+                (route.handler)(message);
+                matched = true;
+            }
+        }
+        matched
+    }
+}
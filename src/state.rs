@@ -0,0 +1,180 @@
+//! Connection state of a client and the ways to observe it.
+//!
+//! The state is moved along by `connect`, `reconnect` and `disconnect`, by
+//! the connect and disconnect callbacks of the network loop and by the
+//! connect timeout. Changes are reported to the state callback and to every
+//! `StateWatcher`.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+///Where the connection to the broker stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    ///Not connected and not trying to. Also after a requested disconnect
+    ///or a connect timeout
+    Disconnected,
+    ///`connect` or `connect_async` was called, waiting for the broker to accept
+    Connecting,
+    ///Broker accepted the connection
+    Connected,
+    ///Connection was lost, the network loop is trying to get it back
+    Reconnecting,
+    ///Client was dropped. Only watchers outlive it to see this
+    Closed,
+}
+
+impl ConnectionState {
+    ///Waiting for a CONNACK, after `connect` or a lost connection
+    pub(crate) fn is_connecting(self) -> bool {
+        matches!(self, ConnectionState::Connecting | ConnectionState::Reconnecting)
+    }
+}
+
+pub type StateCallback = Box<dyn FnMut(ConnectionState) + Send>;
+
+struct Shared {
+    // current state and how many times it changed
+    state: Mutex<(ConnectionState, u64)>,
+    changed: Condvar,
+    callback: Mutex<Option<StateCallback>>,
+}
+
+///Clones share the state, for threads that may outlive the client
+#[derive(Clone)]
+pub struct StateTracker {
+    shared: Arc<Shared>,
+}
+
+impl Default for StateTracker {
+    fn default() -> StateTracker {
+        StateTracker {
+            shared: Arc::new(Shared {
+                state: Mutex::new((ConnectionState::Disconnected, 0)),
+                changed: Condvar::new(),
+                callback: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+impl StateTracker {
+    pub fn get(&self) -> ConnectionState {
+        self.shared.state.lock().unwrap().0
+    }
+
+    ///Moves to `state` and tells the observers, unless it is the current one
+    pub fn set(&self, state: ConnectionState) {
+        {
+            let mut current = self.shared.state.lock().unwrap();
+            if current.0 == state {
+                return;
+            }
+            *current = (state, current.1 + 1);
+        }
+        self.shared.changed.notify_all();
+
+        if let Some(ref mut cb) = *self.shared.callback.lock().unwrap() {
+            cb(state)
+        }
+    }
+
+    pub fn set_callback(&self, callback: StateCallback) {
+        *self.shared.callback.lock().unwrap() = Some(callback);
+    }
+
+    pub fn watcher(&self) -> StateWatcher {
+        let seen = self.shared.state.lock().unwrap().1;
+        StateWatcher {
+            shared: self.shared.clone(),
+            seen,
+        }
+    }
+}
+
+///Watch style handle on the connection state, returned by
+///`MqttClient::state_watcher`. Can be moved to other threads and keeps
+///working after the client is dropped, which it reports as `Closed`.
+///
+///```ignore
+///let mut watcher = client.state_watcher();
+///thread::spawn(move || {
+///    while let Some(state) = watcher.changed(Duration::from_secs(60)) {
+///        println!("@@@ Connection is {:?}", state);
+///        if state == ConnectionState::Closed {
+///            break;
+///        }
+///    }
+///});
+///```
+#[derive(Clone)]
+pub struct StateWatcher {
+    shared: Arc<Shared>,
+    // change count this watcher has seen
+    seen: u64,
+}
+
+impl StateWatcher {
+    ///Current state
+    pub fn get(&self) -> ConnectionState {
+        self.shared.state.lock().unwrap().0
+    }
+
+    ///Waits for the state to change since the watcher was created or last
+    ///returned from here. `None` if nothing changed within `timeout`.
+    ///Changes in quick succession can be folded into the latest one. A
+    ///timeout too large to add to the current time waits without a limit
+    pub fn changed(&mut self, timeout: Duration) -> Option<ConnectionState> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.shared.state.lock().unwrap();
+        while state.1 == self.seen {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.shared.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.shared.changed.wait(state).unwrap(),
+            };
+        }
+        self.seen = state.1;
+        Some(state.0)
+    }
+
+    ///Waits until the client is in `wanted`. Returns false on timeout.
+    ///Timeouts are handled like in `changed`
+    pub fn wait_for(&self, wanted: ConnectionState, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.shared.state.lock().unwrap();
+        while state.0 != wanted {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.shared.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.shared.changed.wait(state).unwrap(),
+            };
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_without_deadline() {
+        let state = StateTracker::default();
+        let mut watcher = state.watcher();
+        state.set(ConnectionState::Connecting);
+        let forever = Duration::from_secs(u64::MAX);
+        assert_eq!(watcher.changed(forever), Some(ConnectionState::Connecting));
+        assert!(watcher.wait_for(ConnectionState::Connecting, forever));
+    }
+}
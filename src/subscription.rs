@@ -0,0 +1,202 @@
+//! Subscription acknowledgements and the registry of subscriptions the
+//! client keeps across reconnects.
+//!
+//! A clean session loses its subscriptions every time libmosquitto
+//! reconnects. The registry remembers filter and QoS of every `subscribe`
+//! and sends them all again after each accepted CONNACK. Subscriptions made
+//! before the first connect are simply queued until then.
+
+use std::ffi::CStr;
+
+use bindings;
+use topic::TopicFilter;
+use Error;
+use Mid;
+use Qos;
+
+///SUBACK return code for a filter the broker refused
+const SUBACK_FAILURE: i32 = 0x80;
+
+///Why the broker didn't grant a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeFailure {
+    ///Broker answered with 0x80, usually because of an ACL
+    Rejected,
+    ///Broker answered with a code that is neither a QoS nor 0x80
+    Unknown(i32),
+}
+
+///Broker response to a subscribe request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscribeAck {
+    ///Message id returned by `subscribe`
+    pub mid: Mid,
+    ///Filter the ack answers. `None` if the client didn't send the request
+    pub filter: Option<String>,
+    ///One entry per requested filter. The granted QoS can be lower than
+    ///the requested one
+    pub granted: Vec<Result<Qos, SubscribeFailure>>,
+}
+
+impl SubscribeAck {
+    pub(crate) fn new(mid: Mid, granted_qos: &[i32]) -> SubscribeAck {
+        let granted = granted_qos.iter()
+                                 .map(|&qos| match Qos::from_int(qos) {
+                                     Some(qos) => Ok(qos),
+                                     None if qos == SUBACK_FAILURE => Err(SubscribeFailure::Rejected),
+                                     None => Err(SubscribeFailure::Unknown(qos)),
+                                 })
+                                 .collect();
+
+        SubscribeAck {
+            mid,
+            filter: None,
+            granted,
+        }
+    }
+
+    ///True if the broker refused at least one filter
+    pub fn is_rejected(&self) -> bool {
+        self.granted.iter().any(|g| g.is_err())
+    }
+}
+
+///Where a subscription stands with the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    ///Waiting for a connection, sent after the next CONNACK
+    Queued,
+    ///SUBSCRIBE with this message id sent, no SUBACK yet
+    Pending(Mid),
+    ///Granted with this QoS
+    Active(Qos),
+    ///Refused by the broker
+    Rejected(SubscribeFailure),
+}
+
+///A subscription the client keeps alive across reconnects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub filter: String,
+    ///Requested QoS
+    pub qos: Qos,
+    pub state: SubscriptionState,
+}
+
+impl Subscription {
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, SubscriptionState::Active(_))
+    }
+}
+
+struct Entry {
+    filter: TopicFilter,
+    qos: Qos,
+    state: SubscriptionState,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    ///Records the subscription and sends it. Returns the message id, or
+    ///`None` if there is no connection and it was queued instead.
+    ///
+    ///Keep the registry locked while calling this so the SUBACK can't be
+    ///handled before the message id is recorded
+    pub unsafe fn subscribe(&mut self,
+                            mosq: *mut bindings::Struct_mosquitto,
+                            filter: TopicFilter,
+                            qos: Qos)
+                            -> Result<Option<Mid>, Error> {
+        let state = match send(mosq, filter.as_c_str(), qos) {
+            Ok(mid) => SubscriptionState::Pending(mid),
+            Err(Error::NoConn) => SubscriptionState::Queued,
+            Err(e) => return Err(e),
+        };
+
+        match self.entries.iter_mut().find(|e| e.filter == filter) {
+            Some(entry) => {
+                entry.qos = qos;
+                entry.state = state;
+            }
+            None => self.entries.push(Entry { filter, qos, state }),
+        }
+
+        match state {
+            SubscriptionState::Pending(mid) => Ok(Some(mid)),
+            _ => Ok(None),
+        }
+    }
+
+    ///Forgets the subscription. Returns false if there was none
+    pub fn unsubscribe(&mut self, filter: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.filter.as_str() != filter);
+        self.entries.len() != before
+    }
+
+    ///Sends every queued subscription. Called after an accepted CONNACK.
+    ///Stops at the first failure, the rest stay queued
+    pub unsafe fn resubscribe(&mut self, mosq: *mut bindings::Struct_mosquitto) -> Result<(), Error> {
+        for entry in self.entries.iter_mut().filter(|e| e.state == SubscriptionState::Queued) {
+            let mid = send(mosq, entry.filter.as_c_str(), entry.qos)?;
+            entry.state = SubscriptionState::Pending(mid);
+        }
+        Ok(())
+    }
+
+    ///Queues everything again. Called when the connection goes away
+    pub fn reset(&mut self) {
+        for entry in &mut self.entries {
+            entry.state = SubscriptionState::Queued;
+        }
+    }
+
+    ///Records the outcome of a SUBACK. Returns the filter it was for
+    pub fn acked(&mut self, ack: &SubscribeAck) -> Option<String> {
+        let entry = self.entries.iter_mut().find(|e| e.state == SubscriptionState::Pending(ack.mid))?;
+        entry.state = match ack.granted.first() {
+            Some(&Ok(qos)) => SubscriptionState::Active(qos),
+            Some(&Err(failure)) => SubscriptionState::Rejected(failure),
+            None => SubscriptionState::Rejected(SubscribeFailure::Unknown(-1)),
+        };
+        Some(entry.filter.as_str().to_string())
+    }
+
+    pub fn list(&self) -> Vec<Subscription> {
+        self.entries
+            .iter()
+            .map(|e| Subscription {
+                filter: e.filter.as_str().to_string(),
+                qos: e.qos,
+                state: e.state,
+            })
+            .collect()
+    }
+}
+
+unsafe fn send(mosq: *mut bindings::Struct_mosquitto, filter: &CStr, qos: Qos) -> Result<Mid, Error> {
+    let mut mid: Mid = 0;
+    Error::from_rc(bindings::mosquitto_subscribe(mosq, &mut mid, filter.as_ptr(), qos.as_int()))?;
+    Ok(mid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suback_codes() {
+        let ack = SubscribeAck::new(3, &[0, 2, 0x80, 0x42]);
+        assert_eq!(ack.granted,
+                   vec![Ok(Qos::AtMostOnce),
+                        Ok(Qos::ExactlyOnce),
+                        Err(SubscribeFailure::Rejected),
+                        Err(SubscribeFailure::Unknown(0x42))]);
+        assert!(ack.is_rejected());
+        assert!(!SubscribeAck::new(4, &[1]).is_rejected());
+    }
+}
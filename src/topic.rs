@@ -0,0 +1,238 @@
+//! Validated topic names and filters, and helpers for building them.
+//!
+//! Validation uses libmosquitto's own checks, so a topic that passes here
+//! won't be refused by `publish` or `subscribe` later on.
+//!
+//!```ignore
+//!let device = topic::escape_level("scooter/42+beta");
+//!let name = TopicName::new(&format!("vehicles/{}/state", device))?;
+//!let filter = TopicFilter::new("vehicles/+/state")?;
+//!assert!(filter.matches(&name));
+//!```
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+
+use bindings;
+use libc;
+use Error;
+
+///Topic a message can be published to. Non empty, no `+` or `#` wildcards
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicName {
+    name: String,
+}
+
+impl TopicName {
+    pub fn new(name: &str) -> Result<TopicName, Error> {
+        checked_name(name)?;
+        Ok(TopicName { name: name.to_string() })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    ///Levels of the topic, split on `/`
+    pub fn levels(&self) -> ::std::str::Split<'_, char> {
+        self.name.split('/')
+    }
+}
+
+///Filter a subscription is made with. Non empty, `+` only as a whole level
+///and `#` only as the whole last level
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicFilter {
+    filter: String,
+    c_filter: CString,
+}
+
+impl TopicFilter {
+    pub fn new(filter: &str) -> Result<TopicFilter, Error> {
+        let c_filter = checked_filter(filter)?;
+        Ok(TopicFilter {
+            filter: filter.to_string(),
+            c_filter,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.filter
+    }
+
+    ///Levels of the filter, split on `/`
+    pub fn levels(&self) -> ::std::str::Split<'_, char> {
+        self.filter.split('/')
+    }
+
+    ///True if a message published to `topic` is delivered to a subscription
+    ///with this filter
+    pub fn matches(&self, topic: &str) -> bool {
+        match CString::new(topic) {
+            Ok(topic) => self.matches_raw(topic.as_ptr()),
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn as_c_str(&self) -> &CStr {
+        &self.c_filter
+    }
+
+    ///`matches` for a NUL terminated topic straight from libmosquitto
+    pub(crate) fn matches_raw(&self, topic: *const libc::c_char) -> bool {
+        let mut result: u8 = 0;
+        let n_ret = unsafe { bindings::mosquitto_topic_matches_sub(self.c_filter.as_ptr(), topic, &mut result) };
+        n_ret == bindings::MOSQ_ERR_SUCCESS && result != 0
+    }
+}
+
+///Validates a topic name and returns it ready to be handed to C
+pub(crate) fn checked_name(name: &str) -> Result<CString, Error> {
+    check(name, bindings::mosquitto_pub_topic_check)
+}
+
+///Validates a topic filter and returns it ready to be handed to C
+pub(crate) fn checked_filter(filter: &str) -> Result<CString, Error> {
+    check(filter, bindings::mosquitto_sub_topic_check)
+}
+
+///Runs one of libmosquitto's topic checks. Empty topics and topics with
+///NUL bytes are refused as well
+fn check(topic: &str, check_fn: unsafe extern "C" fn(*const libc::c_char) -> libc::c_int) -> Result<CString, Error> {
+    let c_topic = match CString::new(topic) {
+        Ok(t) => t,
+        Err(_) => return Err(Error::InvalidTopic(topic.to_string())),
+    };
+    let n_ret = unsafe { check_fn(c_topic.as_ptr()) };
+    if topic.is_empty() || n_ret != bindings::MOSQ_ERR_SUCCESS {
+        return Err(Error::InvalidTopic(topic.to_string()));
+    }
+    Ok(c_topic)
+}
+
+///Splits a topic or filter into its levels with libmosquitto's tokeniser.
+///Empty levels (`a//b`) come back as empty strings
+pub fn tokenise(topic: &str) -> Result<Vec<String>, Error> {
+    let c_topic = CString::new(topic)?;
+
+    let mut tokens: *mut *mut libc::c_char = ptr::null_mut();
+    let mut count: libc::c_int = 0;
+    let n_ret = unsafe { bindings::mosquitto_sub_topic_tokenise(c_topic.as_ptr(), &mut tokens, &mut count) };
+    Error::from_rc(n_ret)?;
+    if tokens.is_null() || count <= 0 {
+        return Ok(Vec::new());
+    }
+
+    let levels = unsafe {
+        slice::from_raw_parts(tokens, count as usize)
+            .iter()
+            .map(|&token| {
+                if token.is_null() {
+                    String::new()
+                } else {
+                    CStr::from_ptr(token).to_string_lossy().into_owned()
+                }
+            })
+            .collect()
+    };
+    unsafe {
+        bindings::mosquitto_sub_topic_tokens_free(&mut tokens, count);
+    }
+    Ok(levels)
+}
+
+///Turns an arbitrary string, like a device id, into something that is safe
+///to use as a single topic level. `/`, `+`, `#`, `$`, NUL and `%` itself are
+///percent encoded. `unescape_level` reverses it
+pub fn escape_level(level: &str) -> String {
+    let mut escaped = String::with_capacity(level.len());
+    for c in level.chars() {
+        match c {
+            '%' | '/' | '+' | '#' | '$' | '\0' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+///Reverses `escape_level`. Malformed escapes are kept as they are
+pub fn unescape_level(level: &str) -> String {
+    let bytes = level.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = level.get(i + 1..i + 3)
+                            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = byte {
+                unescaped.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+impl Deref for TopicName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Deref for TopicFilter {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.filter
+    }
+}
+
+impl AsRef<str> for TopicName {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str {
+        &self.filter
+    }
+}
+
+impl FromStr for TopicName {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<TopicName, Error> {
+        TopicName::new(name)
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = Error;
+
+    fn from_str(filter: &str) -> Result<TopicFilter, Error> {
+        TopicFilter::new(filter)
+    }
+}
+
+impl fmt::Display for TopicName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.filter)
+    }
+}
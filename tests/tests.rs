@@ -1,6 +1,8 @@
 extern crate mosquitto;
 
-use mosquitto::{MqttClient, Qos};
+use mosquitto::{MqttClient, Qos, ConnectReturnCode, ClientOptions, Error, TopicName, TopicFilter, SubscriptionState,
+                ReconnectPolicy, ConnectionState};
+use mosquitto::topic;
 use std::thread;
 use std::time::Duration;
 
@@ -93,27 +95,29 @@ use std::time::Duration;
 ///           AWS client should receive all the messages when broker comes up again. Total count should be = 100
 ///  
 #[test]
+#[allow(clippy::doc_overindented_list_items, clippy::doc_lazy_continuation)]
 fn client_persistance() {
     let mut clients: Vec<MqttClient> = vec![];
 
     for i in 0..10 {
         let id = format!("client-{}", i);
-        let mut client = MqttClient::new(&id, true)
+        let client = MqttClient::new(&id, true)
                              .unwrap()
                              .keep_alive(5)
-                             .will("goodbye", "my last words");
+                             .will("goodbye", b"my last words", Qos::ExactlyOnce, false)
+                             .unwrap();
         clients.push(client);
     }
 
     for client in clients.iter_mut() {
-        client.onconnect_callback(move |a: i32| {
-            println!("@@@ connect callback ret = {} @@@", a);
+        client.onconnect_callback(move |rc: ConnectReturnCode| {
+            println!("@@@ connect callback ret = {} @@@", rc);
         });
     }
 
     // for client in clients.iter_mut() {
     //     match client.connect("ec2-52-77-220-182.ap-southeast-1.compute.amazonaws.com") {
-    //         Ok(_) => println!("Connection successful --> {:?}", client.id),
+    //         Ok(_) => println!("Connection successful --> {:?}", client.id()),
     //         Err(n) => panic!("Connection error = {:?}", n),
     //     }
     // }
@@ -124,23 +128,22 @@ fn client_persistance() {
     //                                 "/home/raviteja/Desktop/certs/ca.crt",
     //                                 Some(("/home/raviteja/Desktop/certs/scooter.crt",
     //                                       "/home/raviteja/Desktop/certs/scooter.key"))) {
-    //         Ok(_) => println!("Connection successful --> {:?}", client.id),
+    //         Ok(_) => println!("Connection successful --> {:?}", client.id()),
     //         Err(n) => panic!("Connection error = {:?}", n),
     //     }
     // }
-    let mid: i32 = 0;
     for client in clients.iter_mut() {
         match client.connect("test.mosquitto.org", 1883) {
-            Ok(_) => println!("Connection successful --> {:?}", client.id),
-            Err(n) => panic!("Connection error = {:?}", n),
+            Ok(_) => println!("Connection successful --> {:?}", client.id()),
+            Err(e) => panic!("Connection error = {}", e),
         }
     }
     let mut count = 0;
     for client in clients.iter_mut() {
         for i in 0..10 {
             // thread::sleep(Duration::from_millis(100));
-            let message = format!("{}...{:?} - Message {}", count, client.id, i);
-            client.publish(Some(&mid), "ather/log-ship", &message, Qos::AtLeastOnce);
+            let message = format!("{}...{:?} - Message {}", count, client.id(), i);
+            client.publish("ather/log-ship", message, Qos::AtLeastOnce, false).unwrap();
             count += 1;
         }
     }
@@ -180,3 +183,115 @@ fn client_persistance() {
 //   - [ ] 'keep alive' of 30 seems to be ok for 300 client connections
 // */
 // }
+
+#[test]
+fn connack_codes() {
+    for rc in 0..7 {
+        assert_eq!(ConnectReturnCode::from(rc).code(), rc);
+    }
+    assert!(ConnectReturnCode::from(0).is_accepted());
+    assert_eq!(ConnectReturnCode::from(5), ConnectReturnCode::NotAuthorized);
+    assert!(!ConnectReturnCode::NotAuthorized.is_temporary());
+}
+
+#[test]
+fn credentials_not_in_debug() {
+    let client = MqttClient::new("credentials-test", true)
+                     .unwrap()
+                     .credentials("scooter", "hunter2")
+                     .unwrap();
+
+    let debug = format!("{:?}", client);
+    assert!(debug.contains("scooter"));
+    assert!(!debug.contains("hunter2"));
+}
+
+#[test]
+fn options_validation() {
+    // libmosquitto makes up an id for clean session clients
+    assert!(ClientOptions::new("").build().is_ok());
+
+    match ClientOptions::new("").clean_session(false).build() {
+        Err(Error::InvalidOption(_)) => (),
+        r => panic!("persistent client without id accepted = {:?}", r),
+    }
+
+    let policy = ReconnectPolicy { initial: 10, max: 5, exponential: true, jitter: 0 };
+    match ClientOptions::new("scooter").reconnect_policy(policy).build() {
+        Err(Error::InvalidOption(_)) => (),
+        r => panic!("reconnect delay larger than max accepted = {:?}", r),
+    }
+
+    match ClientOptions::new("scooter").connect_timeout(Duration::from_secs(0)).build() {
+        Err(Error::InvalidOption(_)) => (),
+        r => panic!("zero connect timeout accepted = {:?}", r),
+    }
+
+    match ClientOptions::new("scooter").will("scooters/+/online", b"0", Qos::AtLeastOnce, true).build() {
+        Err(Error::InvalidTopic(_)) => (),
+        r => panic!("wildcard will topic accepted = {:?}", r),
+    }
+}
+
+#[test]
+fn topics() {
+    assert!(TopicName::new("vehicles/42/state").is_ok());
+    assert!(TopicName::new("vehicles/+/state").is_err());
+    assert!(TopicFilter::new("vehicles/#/state").is_err());
+
+    let filter = TopicFilter::new("vehicles/+/state").unwrap();
+    assert!(filter.matches("vehicles/42/state"));
+    assert!(!filter.matches("vehicles/42/odometer"));
+
+    assert_eq!(topic::tokenise("a//b").unwrap(), vec!["a", "", "b"]);
+
+    let id = "scooter/42+beta%";
+    let level = topic::escape_level(id);
+    assert_eq!(TopicName::new(&format!("vehicles/{}/state", level)).unwrap().levels().count(), 3);
+    assert_eq!(topic::unescape_level(&level), id);
+}
+
+#[test]
+fn subscriptions_queued_before_connect() {
+    let client = MqttClient::new("queued-subscriptions", true).unwrap();
+
+    assert_eq!(client.subscribe("vehicles/+/state", Qos::AtLeastOnce).unwrap(), None);
+    assert_eq!(client.subscribe("vehicles/+/state", Qos::ExactlyOnce).unwrap(), None);
+    assert!(client.subscribe("vehicles/#/state", Qos::AtLeastOnce).is_err());
+
+    let subscriptions = client.subscriptions();
+    assert_eq!(subscriptions.len(), 1);
+    assert_eq!(subscriptions[0].qos, Qos::ExactlyOnce);
+    assert_eq!(subscriptions[0].state, SubscriptionState::Queued);
+
+    assert!(client.unsubscribe("vehicles/+/state").is_err());
+    assert!(client.subscriptions().is_empty());
+}
+
+#[test]
+fn state_watcher_sees_close() {
+    let client = MqttClient::new("state-watcher", true).unwrap();
+    assert_eq!(client.state(), ConnectionState::Disconnected);
+
+    let mut watcher = client.state_watcher();
+    assert_eq!(watcher.changed(Duration::from_millis(10)), None);
+
+    drop(client);
+    assert_eq!(watcher.changed(Duration::from_millis(10)), Some(ConnectionState::Closed));
+    assert!(watcher.wait_for(ConnectionState::Closed, Duration::from_millis(10)));
+}
+
+#[test]
+fn reconnect_after_disconnect() {
+    let mut client = MqttClient::new("reconnect-after-disconnect", true).unwrap();
+    let watcher = client.state_watcher();
+
+    client.connect("test.mosquitto.org", 1883).unwrap();
+    assert!(watcher.wait_for(ConnectionState::Connected, Duration::from_secs(10)));
+
+    client.disconnect().unwrap();
+    assert!(watcher.wait_for(ConnectionState::Disconnected, Duration::from_secs(10)));
+
+    client.reconnect().unwrap();
+    assert!(watcher.wait_for(ConnectionState::Connected, Duration::from_secs(10)));
+}